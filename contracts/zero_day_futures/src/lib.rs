@@ -27,7 +27,7 @@ pub struct TradeEvent { pub buy: Address, pub sell: Address, pub price: i128, pu
 #[derive(SolidityEvent)]
 pub struct LiquidationEvent { #[solidity(indexed)] pub trader: Address, pub mark_price: i128 }
 #[derive(SolidityEvent)]
-pub struct FeeAccrued { pub maker_fee: u128, pub taker_fee: u128 }
+pub struct FeeAccrued { pub maker_fee: i128, pub taker_fee: u128 }
 #[derive(SolidityEvent)]
 pub struct FeesWithdrawn { pub to: Address, pub amount: u128 }
 
@@ -48,8 +48,6 @@ pub struct ZeroDayFutures {
     oracle_ts: StorageMap<u64, u64>,
     default_expiry_secs: StorageU128,
     liquidation_threshold_bps: StorageU128, 
-    maker_fee_bps: StorageU128,
-    taker_fee_bps: StorageU128,
     accrued_fees: StorageU128,
 }
 
@@ -61,8 +59,6 @@ impl ZeroDayFutures {
         self.owner = owner; 
         self.default_expiry_secs.set(86_400); 
         self.liquidation_threshold_bps.set(5_000); 
-        self.maker_fee_bps.set(2); // 0.02%
-        self.taker_fee_bps.set(5); // 0.05%
     }
 
     fn ensure_owner(&self) -> Result<(), ContractError> { if stylus_sdk::msg::sender() != self.owner { return Err(ContractError::NotOwner);} Ok(()) }
@@ -110,7 +106,13 @@ impl ZeroDayFutures {
     Ok(id)
     }
 
-    pub fn match_orders(&mut self, buy_id: u64, sell_id: u64, price: i128) -> Result<(), ContractError> {
+    /// `maker_is_buy` says which order was resting first, as decided by the
+    /// off-chain matcher; that side pays `maker_fee` and the other `taker_fee`.
+    /// The fees are the amounts the matcher charged (tiers and overrides
+    /// included); a negative `maker_fee` is a rebate. Owner only: the caller
+    /// sets the fees, so anyone else could pay themselves the fees held.
+    pub fn match_orders(&mut self, buy_id: u64, sell_id: u64, price: i128, maker_is_buy: bool, maker_fee: i128, taker_fee: i128) -> Result<(), ContractError> {
+        self.ensure_owner()?;
         self.ensure_not_paused()?;
         let now = stylus_sdk::block::timestamp();
         let buy = self.orders.get(&buy_id).ok_or(ContractError::OrderExpired)?;
@@ -120,14 +122,19 @@ impl ZeroDayFutures {
         let qty = core::cmp::min(buy.data.qty.abs(), sell.data.qty.abs());
         self.apply_fill(&buy.data, price, qty);
         self.apply_fill(&sell.data, price, qty);
-        // fee calc (simplified: maker = order with older id)
-        let maker_is_buy = buy_id < sell_id; // naive heuristic
-        let notional = (price.abs() as u128) * (qty.abs() as u128);
-        let maker_fee = notional * self.maker_fee_bps.get() / 10_000;
-        let taker_fee = notional * self.taker_fee_bps.get() / 10_000;
-        let total = maker_fee + taker_fee;
-        let accrued = self.accrued_fees.get();
-        self.accrued_fees.set(accrued + total);
+        let (maker, taker) = if maker_is_buy { (buy.data.trader, sell.data.trader) } else { (sell.data.trader, buy.data.trader) };
+        // same caps as the matcher: a fee takes at most the payer's collateral, a rebate at most the fees held
+        let taker_fee = self.charge_fee(taker, taker_fee.max(0) as u128);
+        let held = self.accrued_fees.get() + taker_fee;
+        let maker_fee = if maker_fee >= 0 {
+            self.charge_fee(maker, maker_fee as u128) as i128
+        } else {
+            let rebate = core::cmp::min((-maker_fee) as u128, held);
+            let coll = self.collateral.get(&maker).unwrap_or_default();
+            self.collateral.insert(maker, coll + rebate);
+            -(rebate as i128)
+        };
+        self.accrued_fees.set((held as i128 + maker_fee) as u128);
         FeeAccrued { maker_fee, taker_fee }.emit();
         TradeEvent { buy: buy.data.trader, sell: sell.data.trader, price, qty }.emit();
         // remove orders for demo
@@ -136,6 +143,14 @@ impl ZeroDayFutures {
         Ok(())
     }
 
+    // take a fee out of a trader's collateral, at most what they have; returns what was taken
+    fn charge_fee(&mut self, trader: Address, fee: u128) -> u128 {
+        let coll = self.collateral.get(&trader).unwrap_or_default();
+        let fee = if fee > coll { coll } else { fee };
+        self.collateral.insert(trader, coll - fee);
+        fee
+    }
+
     fn apply_fill(&mut self, order: &OrderData, price: i128, qty: i128) {
         let pos_qty = self.position_qty.get(&order.trader).unwrap_or_default();
         let entry = self.position_entry.get(&order.trader).unwrap_or_default();
@@ -181,7 +196,6 @@ impl ZeroDayFutures {
         for t in traders.into_iter() { self.try_liquidate(t, mark_price); }
     }
 
-    pub fn set_fees(&mut self, maker_bps: u128, taker_bps: u128) -> Result<(), ContractError> { self.ensure_owner()?; self.maker_fee_bps.set(maker_bps); self.taker_fee_bps.set(taker_bps); Ok(()) }
     pub fn withdraw_fees(&mut self, to: Address, amount: u128) -> Result<(), ContractError> { self.ensure_owner()?; let acc = self.accrued_fees.get(); let a = if amount>acc {acc} else {amount}; self.accrued_fees.set(acc - a); stylus_sdk::msg::send(to, a); FeesWithdrawn{ to, amount:a }.emit(); Ok(()) }
 
     pub fn update_oracle_price(&mut self, product_id: u64, price: i128) -> Result<(), ContractError> {
@@ -199,10 +213,9 @@ impl ZeroDayFutures {
     pub fn ext_deposit(&mut self) -> Result<(), ContractError> { self.deposit() }
     pub fn ext_withdraw(&mut self, amount: u128) -> Result<(), ContractError> { self.withdraw(amount) }
     pub fn ext_place_order(&mut self, side: u8, price: i128, qty: i128, leverage: u32) -> Result<u64, ContractError> { self.place_order(side, price, qty, leverage) }
-    pub fn ext_match(&mut self, buy_id: u64, sell_id: u64, price: i128) -> Result<(), ContractError> { self.match_orders(buy_id, sell_id, price) }
+    pub fn ext_match(&mut self, buy_id: u64, sell_id: u64, price: i128, maker_is_buy: bool, maker_fee: i128, taker_fee: i128) -> Result<(), ContractError> { self.match_orders(buy_id, sell_id, price, maker_is_buy, maker_fee, taker_fee) }
     pub fn ext_liquidate(&mut self, trader: Address, mark_price: i128) { self.try_liquidate(trader, mark_price) }
     pub fn ext_update_oracle(&mut self, product_id: u64, price: i128) -> Result<(), ContractError> { self.update_oracle_price(product_id, price) }
     pub fn ext_batch_liquidate(&mut self, traders: Vec<Address>, mark_price: i128) { self.batch_liquidate(traders, mark_price) }
-    pub fn ext_set_fees(&mut self, maker_bps: u128, taker_bps: u128) -> Result<(), ContractError> { self.set_fees(maker_bps, taker_bps) }
     pub fn ext_withdraw_fees(&mut self, to: Address, amount: u128) -> Result<(), ContractError> { self.withdraw_fees(to, amount) }
 }
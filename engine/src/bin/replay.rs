@@ -0,0 +1,60 @@
+// Replay a command log through the engine and print what it produces: one
+// JSON line per event (tagged with the seq and ts of the command that caused
+// it), then the final state. The log is the matcher's `wal.log`; pass its
+// `snapshot.json` with --snapshot to start from there instead of empty.
+//
+//   replay [--snapshot data/snapshot.json] data/wal.log
+use engine::{EngineConfig, EngineEvent, EngineState, LogEntry, Snapshot};
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Write};
+
+#[derive(Serialize)]
+struct Out<'a> {
+    seq: u64,
+    ts: u64,
+    #[serde(flatten)]
+    event: &'a EngineEvent,
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (mut snapshot, mut log) = (None, None);
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "--snapshot" => snapshot = args.next(),
+            "-h" | "--help" => return usage(),
+            _ => log = Some(a),
+        }
+    }
+    let Some(log) = log else { return usage() };
+
+    let (mut state, last_seq) = match snapshot {
+        Some(path) => {
+            let s: Snapshot = serde_json::from_slice(&std::fs::read(&path).unwrap_or_else(|e| fail(&path, e))).unwrap_or_else(|e| fail(&path, e));
+            (s.state, s.last_seq)
+        }
+        None => (EngineState::new(EngineConfig::default()), 0),
+    };
+    let file = std::fs::File::open(&log).unwrap_or_else(|e| fail(&log, e));
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.unwrap_or_else(|e| fail(&log, e));
+        if line.trim().is_empty() { continue; }
+        let entry: LogEntry = serde_json::from_str(&line).unwrap_or_else(|e| fail(&format!("{} line {}", log, n + 1), e));
+        if entry.seq <= last_seq { continue; }
+        for event in state.apply(entry.cmd, entry.ts) {
+            let _ = writeln!(out, "{}", serde_json::to_string(&Out { seq: entry.seq, ts: entry.ts, event: &event }).unwrap_or_default());
+        }
+    }
+    let _ = writeln!(out, "{}", serde_json::to_string_pretty(&state).unwrap_or_default());
+}
+
+fn usage() {
+    eprintln!("usage: replay [--snapshot snapshot.json] wal.log");
+}
+
+fn fail(what: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", what, e);
+    std::process::exit(1);
+}
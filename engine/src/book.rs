@@ -0,0 +1,286 @@
+use std::collections::{BTreeMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use crate::{Order, Side, StpMode};
+
+/// FIFO book for the single demo product. Each side is a queue of
+/// (order id, order); matching always looks at the front of both queues.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OrderBook {
+    pub buys: VecDeque<(u64, Order)>,
+    pub sells: VecDeque<(u64, Order)>,
+}
+
+impl OrderBook {
+    pub fn push(&mut self, id: u64, order: Order) {
+        match order.side {
+            Side::Buy => self.buys.push_back((id, order)),
+            Side::Sell => self.sells.push_back((id, order)),
+        }
+    }
+
+    pub fn best_buy(&self) -> Option<&(u64, Order)> { self.buys.front() }
+    pub fn best_sell(&self) -> Option<&(u64, Order)> { self.sells.front() }
+
+    fn queue_mut(&mut self, side: Side) -> &mut VecDeque<(u64, Order)> {
+        match side { Side::Buy => &mut self.buys, Side::Sell => &mut self.sells }
+    }
+
+    /// Apply a fill of `qty` against the visible slice of order `id`.
+    /// A fully consumed order leaves the book; a consumed iceberg slice is
+    /// refilled from the hidden reserve and re-queued at the back, so the
+    /// refill loses time priority like a fresh order would.
+    /// Returns false if the order is no longer on the `side` queue.
+    pub fn fill(&mut self, side: Side, id: u64, qty: i128) -> bool {
+        let queue = self.queue_mut(side);
+        let Some(idx) = queue.iter().position(|(oid, _)| *oid == id) else { return false; };
+        let order = &mut queue[idx].1;
+        order.qty -= qty.min(order.qty);
+        if order.qty > 0 { return true; }
+        let (oid, mut order) = queue.remove(idx).expect("index in range");
+        if order.replenish() { queue.push_back((oid, order)); }
+        true
+    }
+}
+
+/// Quantity taken off a resting order outside of a trade (cancel, self-trade
+/// prevention). `order` is the state before the reduction; callers use it to
+/// release the margin locked for `qty`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cancelled {
+    pub id: u64,
+    pub order: Order,
+    pub qty: i128,
+}
+
+impl OrderBook {
+    /// Remove order `id` from whichever side holds it.
+    pub fn cancel(&mut self, id: u64) -> Option<Cancelled> {
+        for queue in [&mut self.buys, &mut self.sells] {
+            if let Some(idx) = queue.iter().position(|(oid, _)| *oid == id) {
+                let (id, order) = queue.remove(idx).expect("index in range");
+                let qty = order.remaining_qty();
+                return Some(Cancelled { id, order, qty });
+            }
+        }
+        None
+    }
+
+    fn reduce(&mut self, side: Side, id: u64, qty: i128) -> Option<Cancelled> {
+        let queue = self.queue_mut(side);
+        let idx = queue.iter().position(|(oid, _)| *oid == id)?;
+        let before = queue[idx].1.clone();
+        queue[idx].1.reduce(qty);
+        if queue[idx].1.remaining_qty() <= 0 {
+            queue.remove(idx);
+        } else if queue[idx].1.qty <= 0 {
+            let (oid, mut order) = queue.remove(idx).expect("index in range");
+            order.replenish();
+            queue.push_back((oid, order));
+        }
+        Some(Cancelled { id, order: before, qty })
+    }
+
+    /// Resolve a would-be self trade between resting buy `buy_id` and sell
+    /// `sell_id` using the newer order's STP mode. Ids need not follow arrival
+    /// order, so the newer order is the one with the larger `seq` (orders from
+    /// before `seq` existed have 0 and fall back to the id). Returns what was
+    /// taken off the book.
+    pub fn prevent_self_trade(&mut self, buy_id: u64, sell_id: u64) -> Vec<Cancelled> {
+        let (Some(buy), Some(sell)) = (self.find(Side::Buy, buy_id), self.find(Side::Sell, sell_id)) else { return Vec::new(); };
+        let (buy_qty, sell_qty) = (buy.remaining_qty(), sell.remaining_qty());
+        let (newest, oldest) = if (buy.seq, buy_id) > (sell.seq, sell_id) { ((Side::Buy, buy_id), (Side::Sell, sell_id)) } else { ((Side::Sell, sell_id), (Side::Buy, buy_id)) };
+        let mode = if newest.0 == Side::Buy { buy.stp } else { sell.stp };
+        let mut out = Vec::new();
+        match mode {
+            StpMode::CancelNewest => out.extend(self.cancel(newest.1)),
+            StpMode::CancelOldest => out.extend(self.cancel(oldest.1)),
+            StpMode::CancelBoth => {
+                out.extend(self.cancel(newest.1));
+                out.extend(self.cancel(oldest.1));
+            }
+            StpMode::DecrementAndCancel => {
+                let dec = buy_qty.min(sell_qty);
+                out.extend(self.reduce(Side::Buy, buy_id, dec));
+                out.extend(self.reduce(Side::Sell, sell_id, dec));
+            }
+        }
+        out
+    }
+
+    /// Ids of resting orders whose expiry is at or before `now`, bids first.
+    pub fn expired(&self, now: u64) -> Vec<u64> {
+        self.buys.iter().chain(self.sells.iter()).filter(|(_, o)| o.expiry_ts <= now).map(|(id, _)| *id).collect()
+    }
+
+    /// Look up a resting order on either side.
+    pub fn get(&self, id: u64) -> Option<&Order> {
+        self.find(Side::Buy, id).or_else(|| self.find(Side::Sell, id))
+    }
+
+    fn find(&self, side: Side, id: u64) -> Option<&Order> {
+        let queue = match side { Side::Buy => &self.buys, Side::Sell => &self.sells };
+        queue.iter().find(|(oid, _)| *oid == id).map(|(_, o)| o)
+    }
+
+    /// Visible quantity aggregated per price, best first (highest bid,
+    /// lowest ask). Iceberg reserves are not shown.
+    pub fn depth(&self, side: Side) -> Vec<Level> {
+        let queue = match side { Side::Buy => &self.buys, Side::Sell => &self.sells };
+        let mut levels: BTreeMap<i128, i128> = BTreeMap::new();
+        for (_, o) in queue.iter().filter(|(_, o)| o.qty > 0) {
+            *levels.entry(o.price).or_insert(0) += o.qty;
+        }
+        let levels = levels.into_iter().map(|(price, qty)| Level { price, qty });
+        match side {
+            Side::Buy => levels.rev().collect(),
+            Side::Sell => levels.collect(),
+        }
+    }
+}
+
+/// One aggregated price level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Level {
+    pub price: i128,
+    pub qty: i128,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Iceberg;
+
+    fn order(trader: &str, side: Side, qty: i128) -> Order {
+        Order { trader: trader.into(), side, price: 100, qty, leverage: 10, ts: 0, expiry_ts: 86_400, is_limit: true, iceberg: None, stp: StpMode::default(), client_order_id: None, seq: 0, onchain_id: None }
+    }
+
+    fn ids(q: &VecDeque<(u64, Order)>) -> Vec<u64> { q.iter().map(|(id, _)| *id).collect() }
+
+    #[test]
+    fn test_partial_fill_keeps_remainder() {
+        let mut ob = OrderBook::default();
+        ob.push(1, order("a", Side::Buy, 500));
+        assert!(ob.fill(Side::Buy, 1, 200));
+        assert_eq!(ob.best_buy().map(|(_, o)| o.qty), Some(300));
+        assert!(ob.fill(Side::Buy, 1, 300));
+        assert!(ob.best_buy().is_none());
+    }
+
+    #[test]
+    fn test_iceberg_replenish_loses_priority() {
+        let mut ob = OrderBook::default();
+        let ice = Order::with_display(order("a", Side::Sell, 1_000), 300);
+        assert_eq!(ice.qty, 300);
+        assert_eq!(ice.iceberg, Some(Iceberg { display_qty: 300, hidden_qty: 700 }));
+        ob.push(1, ice);
+        ob.push(2, order("b", Side::Sell, 100));
+        assert!(ob.fill(Side::Sell, 1, 300));
+        // slice refilled from the reserve and moved behind order 2
+        assert_eq!(ids(&ob.sells), vec![2, 1]);
+        let (_, refilled) = ob.sells.back().unwrap();
+        assert_eq!(refilled.qty, 300);
+        assert_eq!(refilled.remaining_qty(), 700);
+    }
+
+    #[test]
+    fn test_iceberg_last_slice_is_partial() {
+        let mut ob = OrderBook::default();
+        ob.push(1, Order::with_display(order("a", Side::Buy, 500), 200));
+        assert!(ob.fill(Side::Buy, 1, 200));
+        assert!(ob.fill(Side::Buy, 1, 200));
+        assert_eq!(ob.best_buy().map(|(_, o)| o.qty), Some(100));
+        assert!(ob.fill(Side::Buy, 1, 100));
+        assert!(ob.best_buy().is_none());
+    }
+
+    fn stp_order(side: Side, qty: i128, stp: StpMode) -> Order {
+        Order { stp, ..order("alice", side, qty) }
+    }
+
+    #[test]
+    fn test_cancel_by_id() {
+        let mut ob = OrderBook::default();
+        ob.push(1, order("a", Side::Buy, 100));
+        ob.push(2, Order::with_display(order("a", Side::Sell, 500), 100));
+        assert!(ob.get(2).is_some());
+        let c = ob.cancel(2).unwrap();
+        assert_eq!((c.id, c.qty), (2, 500));
+        assert!(ob.get(2).is_none());
+        assert!(ob.cancel(2).is_none());
+        assert_eq!(ids(&ob.buys), vec![1]);
+    }
+
+    #[test]
+    fn test_cancel_newest_uses_newer_orders_mode() {
+        let mut ob = OrderBook::default();
+        ob.push(1, stp_order(Side::Buy, 100, StpMode::CancelBoth));
+        ob.push(2, stp_order(Side::Sell, 100, StpMode::CancelNewest));
+        let out = ob.prevent_self_trade(1, 2);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].id, 2);
+        assert_eq!(ids(&ob.buys), vec![1]);
+        assert!(ob.sells.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_oldest_and_both() {
+        let mut ob = OrderBook::default();
+        ob.push(1, stp_order(Side::Buy, 100, StpMode::CancelNewest));
+        ob.push(2, stp_order(Side::Sell, 100, StpMode::CancelOldest));
+        assert_eq!(ob.prevent_self_trade(1, 2)[0].id, 1);
+        assert!(ob.buys.is_empty());
+
+        ob.push(3, stp_order(Side::Buy, 100, StpMode::CancelBoth));
+        assert_eq!(ob.prevent_self_trade(3, 2).len(), 2);
+        assert!(ob.buys.is_empty() && ob.sells.is_empty());
+    }
+
+    #[test]
+    fn test_newest_is_by_arrival_not_id() {
+        let mut ob = OrderBook::default();
+        // the buy arrived second but is booked under the lower id
+        ob.push(7, Order { seq: 2, ..stp_order(Side::Buy, 100, StpMode::CancelNewest) });
+        ob.push(9, Order { seq: 1, ..stp_order(Side::Sell, 100, StpMode::CancelBoth) });
+        let out = ob.prevent_self_trade(7, 9);
+        assert_eq!(out.iter().map(|c| c.id).collect::<Vec<_>>(), vec![7]);
+        assert_eq!(ids(&ob.sells), vec![9]);
+
+        ob.push(3, Order { seq: 3, ..stp_order(Side::Buy, 100, StpMode::CancelOldest) });
+        assert_eq!(ob.prevent_self_trade(3, 9)[0].id, 9);
+        assert_eq!(ids(&ob.buys), vec![3]);
+    }
+
+    #[test]
+    fn test_decrement_and_cancel() {
+        let mut ob = OrderBook::default();
+        ob.push(1, stp_order(Side::Buy, 300, StpMode::CancelNewest));
+        ob.push(2, stp_order(Side::Sell, 100, StpMode::DecrementAndCancel));
+        let out = ob.prevent_self_trade(1, 2);
+        assert_eq!(out.iter().map(|c| (c.id, c.qty)).collect::<Vec<_>>(), vec![(1, 100), (2, 100)]);
+        assert_eq!(ob.best_buy().map(|(_, o)| o.qty), Some(200));
+        assert!(ob.sells.is_empty());
+    }
+
+    #[test]
+    fn test_depth_aggregates_visible_qty() {
+        let mut ob = OrderBook::default();
+        ob.push(1, Order { price: 99, ..order("a", Side::Buy, 100) });
+        ob.push(2, Order { price: 101, ..order("b", Side::Buy, 50) });
+        ob.push(3, Order { price: 99, ..order("c", Side::Buy, 500) }.with_display(25));
+        ob.push(4, Order { price: 103, ..order("d", Side::Sell, 10) });
+        ob.push(5, Order { price: 102, ..order("e", Side::Sell, 20) });
+        let lv = |price, qty| Level { price, qty };
+        assert_eq!(ob.depth(Side::Buy), vec![lv(101, 50), lv(99, 125)]);
+        assert_eq!(ob.depth(Side::Sell), vec![lv(102, 20), lv(103, 10)]);
+    }
+
+    #[test]
+    fn test_decrement_takes_iceberg_reserve_first() {
+        let mut ob = OrderBook::default();
+        ob.push(1, stp_order(Side::Buy, 500, StpMode::CancelNewest).with_display(100));
+        ob.push(2, stp_order(Side::Sell, 300, StpMode::DecrementAndCancel));
+        ob.prevent_self_trade(1, 2);
+        let (_, b) = ob.best_buy().unwrap();
+        assert_eq!((b.qty, b.remaining_qty()), (100, 200));
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeSet, VecDeque};
+use serde::{Deserialize, Serialize};
+
+/// Candle width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Interval {
+    #[serde(rename = "1s")] S1,
+    #[serde(rename = "1m")] M1,
+    #[serde(rename = "5m")] M5,
+    #[serde(rename = "1h")] H1,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 4] = [Interval::S1, Interval::M1, Interval::M5, Interval::H1];
+
+    pub fn secs(self) -> u64 {
+        match self { Interval::S1 => 1, Interval::M1 => 60, Interval::M5 => 300, Interval::H1 => 3_600 }
+    }
+}
+
+/// OHLCV bucket starting at `start` (unix secs, aligned to the interval).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candle {
+    pub start: u64,
+    pub open: i128,
+    pub high: i128,
+    pub low: i128,
+    pub close: i128,
+    pub volume: i128,
+    pub trades: u64,
+}
+
+/// Candles at every interval for one price source. Buckets with no ticks are
+/// skipped rather than filled; each interval keeps at most `cap` candles.
+#[derive(Debug, Clone)]
+pub struct Candles {
+    cap: usize,
+    series: Vec<(Interval, VecDeque<Candle>)>,
+    dirty: BTreeSet<Interval>,
+}
+
+impl Candles {
+    pub fn new(cap: usize) -> Self {
+        Self { cap: cap.max(1), series: Interval::ALL.iter().map(|i| (*i, VecDeque::new())).collect(), dirty: BTreeSet::new() }
+    }
+
+    /// Rebuild from `(ts, price, qty)` ticks in time order, e.g. the trade history after a restart.
+    pub fn rebuild(cap: usize, ticks: impl IntoIterator<Item = (u64, i128, i128)>) -> Self {
+        let mut c = Self::new(cap);
+        for (ts, price, qty) in ticks { c.record(ts, price, qty); }
+        c.dirty.clear();
+        c
+    }
+
+    /// Fold a tick into every interval. `qty` 0 is a price-only tick (oracle)
+    /// and does not count as a trade. A tick older than the current bucket is
+    /// folded into the current bucket.
+    pub fn record(&mut self, ts: u64, price: i128, qty: i128) {
+        for (interval, candles) in self.series.iter_mut() {
+            let start = ts - ts % interval.secs();
+            match candles.back_mut() {
+                Some(c) if c.start >= start => {
+                    c.high = c.high.max(price);
+                    c.low = c.low.min(price);
+                    c.close = price;
+                    c.volume += qty.abs();
+                    c.trades += (qty != 0) as u64;
+                }
+                _ => {
+                    candles.push_back(Candle { start, open: price, high: price, low: price, close: price, volume: qty.abs(), trades: (qty != 0) as u64 });
+                    if candles.len() > self.cap { candles.pop_front(); }
+                }
+            }
+            self.dirty.insert(*interval);
+        }
+    }
+
+    /// Candles of `interval` with `from <= start <= to`, oldest first.
+    pub fn range(&self, interval: Interval, from: u64, to: u64) -> impl Iterator<Item = &Candle> {
+        self.get(interval).iter().filter(move |c| c.start >= from && c.start <= to)
+    }
+
+    /// Latest candle of every interval touched since the previous call.
+    pub fn take_updates(&mut self) -> Vec<(Interval, Candle)> {
+        let dirty = std::mem::take(&mut self.dirty);
+        dirty.into_iter().filter_map(|i| self.get(i).back().map(|c| (i, *c))).collect()
+    }
+
+    fn get(&self, interval: Interval) -> &VecDeque<Candle> {
+        &self.series.iter().find(|(i, _)| *i == interval).expect("every interval has a series").1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ohlcv_buckets() {
+        let mut c = Candles::new(100);
+        c.record(60, 100, 2);
+        c.record(75, 104, 1);
+        c.record(90, 97, 3);
+        c.record(119, 101, 0); // price-only tick
+        c.record(120, 99, 5);
+        let m1: Vec<Candle> = c.range(Interval::M1, 0, u64::MAX).copied().collect();
+        assert_eq!(m1, vec![
+            Candle { start: 60, open: 100, high: 104, low: 97, close: 101, volume: 6, trades: 3 },
+            Candle { start: 120, open: 99, high: 99, low: 99, close: 99, volume: 5, trades: 1 },
+        ]);
+        assert_eq!(c.range(Interval::S1, 0, u64::MAX).count(), 5);
+        assert_eq!(c.range(Interval::M5, 0, u64::MAX).map(|c| (c.start, c.volume)).collect::<Vec<_>>(), vec![(0, 11)]);
+        assert_eq!(c.range(Interval::S1, 75, 100).count(), 2);
+    }
+
+    #[test]
+    fn test_cap_and_updates() {
+        let mut c = Candles::new(2);
+        for ts in [1, 2, 3] { c.record(ts, 100, 1); }
+        assert_eq!(c.range(Interval::S1, 0, u64::MAX).map(|c| c.start).collect::<Vec<_>>(), vec![2, 3]);
+        let updates = c.take_updates();
+        assert_eq!(updates.len(), 4);
+        assert_eq!(updates[0], (Interval::S1, Candle { start: 3, open: 100, high: 100, low: 100, close: 100, volume: 1, trades: 1 }));
+        assert!(c.take_updates().is_empty());
+    }
+
+    #[test]
+    fn test_rebuild_matches_live() {
+        let ticks = [(5, 100, 1), (61, 102, 2), (3_700, 98, 1)];
+        let mut live = Candles::new(10);
+        for (ts, p, q) in ticks { live.record(ts, p, q); }
+        let rebuilt = Candles::rebuild(10, ticks);
+        for i in Interval::ALL {
+            assert!(live.range(i, 0, u64::MAX).eq(rebuilt.range(i, 0, u64::MAX)));
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of "now" in unix seconds. The engine itself never reads a clock;
+/// whoever feeds it commands stamps them from one of these, so tests and
+/// simulations can control time without touching the engine.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// Wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+/// Only moves when told to. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+
+impl ManualClock {
+    pub fn new(start: u64) -> Self { Self(Arc::new(AtomicU64::new(start))) }
+    pub fn set(&self, now: u64) { self.0.store(now, Ordering::SeqCst); }
+    pub fn advance(&self, secs: u64) { self.0.fetch_add(secs, Ordering::SeqCst); }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 { self.0.load(Ordering::SeqCst) }
+}
+
+/// Starts at `start` and runs `speed` times faster than real time, e.g. to
+/// let a simulation reach order and position expiries in minutes.
+#[derive(Debug, Clone)]
+pub struct AcceleratedClock {
+    start: u64,
+    origin: Instant,
+    speed: u64,
+}
+
+impl AcceleratedClock {
+    pub fn new(start: u64, speed: u64) -> Self { Self { start, origin: Instant::now(), speed: speed.max(1) } }
+}
+
+impl Clock for AcceleratedClock {
+    fn now(&self) -> u64 {
+        let elapsed = self.origin.elapsed().as_millis() as u64;
+        self.start + elapsed.saturating_mul(self.speed) / 1_000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_is_shared() {
+        let c = ManualClock::new(1_000);
+        let other = c.clone();
+        c.advance(30);
+        assert_eq!(other.now(), 1_030);
+        other.set(5);
+        assert_eq!(c.now(), 5);
+    }
+
+    #[test]
+    fn test_accelerated_clock_runs_fast() {
+        let c = AcceleratedClock::new(1_000, 10_000);
+        assert!(c.now() < 1_000 + 10_000);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(c.now() >= 1_000 + 200);
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// Currency positions, PnL and fees settle in; held in `Account.collateral`.
+pub const SETTLEMENT_ASSET: &str = "USD";
+
+/// Where a collateral asset's price comes from.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSource {
+    /// Set with the asset and never moves, e.g. a stablecoin at 1.
+    Fixed,
+    /// Pushed with `SetCollateralPrice`, e.g. from an external feed.
+    Feed,
+    /// The product's oracle mark.
+    Mark,
+}
+
+/// An asset accounts can hold as collateral besides the settlement currency.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CollateralAsset {
+    pub haircut_bps: u64, // share of the value that does not count towards equity
+    pub source: PriceSource,
+    #[serde(default)]
+    pub price: i128, // settlement units per unit of the asset; unused for `Mark`
+    #[serde(default)]
+    pub price_ts: u64,
+}
+
+impl CollateralAsset {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.haircut_bps > 10_000 { return Err("haircut_bps must be in 0..=10000"); }
+        if self.price < 0 { return Err("price must not be negative"); }
+        if self.source == PriceSource::Fixed && self.price == 0 { return Err("a fixed price is required"); }
+        Ok(())
+    }
+
+    pub fn price(&self, mark: i128) -> i128 {
+        if self.source == PriceSource::Mark { mark } else { self.price }
+    }
+
+    /// What `amount` of the asset counts for in settlement currency, after the haircut.
+    pub fn value(&self, amount: i128, mark: i128) -> i128 {
+        amount * self.price(mark) * (10_000 - self.haircut_bps.min(10_000)) as i128 / 10_000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_after_haircut() {
+        let eth = CollateralAsset { haircut_bps: 2_000, source: PriceSource::Feed, price: 3_000, price_ts: 0 };
+        assert_eq!(eth.value(2, 100), 4_800);
+        let own = CollateralAsset { source: PriceSource::Mark, ..eth.clone() };
+        assert_eq!(own.value(10, 100), 800);
+        assert!(CollateralAsset { source: PriceSource::Fixed, price: 0, ..eth.clone() }.validate().is_err());
+        assert!(CollateralAsset { haircut_bps: 10_001, ..eth }.validate().is_err());
+    }
+}
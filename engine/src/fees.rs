@@ -0,0 +1,125 @@
+use std::collections::{BTreeMap, VecDeque};
+use serde::{Deserialize, Serialize};
+
+const DAY_SECS: u64 = 86_400;
+/// Traded volume that decides a trader's tier is summed over this many days.
+pub const VOLUME_WINDOW_DAYS: u64 = 30;
+
+/// Fee rates in basis points of notional. A negative maker rate is a rebate.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FeeRates {
+    pub maker_bps: i64,
+    pub taker_bps: i64,
+}
+
+impl FeeRates {
+    /// Takers always pay, and a maker rebate is never more than a taker
+    /// pays at the same rates.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(0..=10_000).contains(&self.taker_bps) { return Err("taker_bps must be in 0..=10000"); }
+        if self.maker_bps > 10_000 || self.maker_bps < -self.taker_bps { return Err("maker_bps must be in -taker_bps..=10000"); }
+        Ok(())
+    }
+}
+
+/// Rates for traders whose 30-day volume is at least `min_volume` (notional).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FeeTier {
+    pub min_volume: i128,
+    #[serde(flatten)]
+    pub rates: FeeRates,
+}
+
+/// Volume tiers, lowest first; the first starts at 0 so every trader has one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FeeSchedule {
+    pub tiers: Vec<FeeTier>,
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self { Self::flat(2, 5) }
+}
+
+impl FeeSchedule {
+    /// One tier for everyone.
+    pub fn flat(maker_bps: i64, taker_bps: i64) -> Self {
+        Self { tiers: vec![FeeTier { min_volume: 0, rates: FeeRates { maker_bps, taker_bps } }] }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.tiers.first().map(|t| t.min_volume) != Some(0) { return Err("the first tier must start at min_volume 0"); }
+        if self.tiers.windows(2).any(|w| w[1].min_volume <= w[0].min_volume) { return Err("min_volume must increase from tier to tier"); }
+        self.tiers.iter().try_for_each(|t| t.rates.validate())
+    }
+
+    /// Index and rates of the highest tier `volume` reaches.
+    pub fn tier_for(&self, volume: i128) -> (usize, FeeRates) {
+        let i = self.tiers.iter().rposition(|t| volume >= t.min_volume).unwrap_or(0);
+        (i, self.tiers.get(i).map(|t| t.rates).unwrap_or(FeeRates { maker_bps: 0, taker_bps: 0 }))
+    }
+}
+
+/// Traded notional per trader in daily buckets, oldest first; buckets that
+/// fall out of the window are dropped as new ones are added.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VolumeWindow(BTreeMap<String, VecDeque<(u64, i128)>>);
+
+impl VolumeWindow {
+    pub fn record(&mut self, trader: &str, now: u64, notional: i128) {
+        let day = now / DAY_SECS;
+        let buckets = self.0.entry(trader.to_string()).or_default();
+        match buckets.back_mut() {
+            Some((d, v)) if *d == day => *v += notional,
+            _ => buckets.push_back((day, notional)),
+        }
+        while buckets.front().map(|(d, _)| d + VOLUME_WINDOW_DAYS <= day).unwrap_or(false) { buckets.pop_front(); }
+    }
+
+    /// Notional traded over the last `VOLUME_WINDOW_DAYS` days, today included.
+    pub fn volume(&self, trader: &str, now: u64) -> i128 {
+        let day = now / DAY_SECS;
+        self.0.get(trader).map(|b| b.iter().filter(|(d, _)| d + VOLUME_WINDOW_DAYS > day).map(|(_, v)| v).sum()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(min_volume: i128, maker_bps: i64, taker_bps: i64) -> FeeTier {
+        FeeTier { min_volume, rates: FeeRates { maker_bps, taker_bps } }
+    }
+
+    #[test]
+    fn test_tier_for_volume() {
+        let s = FeeSchedule { tiers: vec![tier(0, 2, 5), tier(1_000_000, 0, 4), tier(10_000_000, -1, 3)] };
+        assert_eq!(s.validate(), Ok(()));
+        assert_eq!(s.tier_for(999_999), (0, FeeRates { maker_bps: 2, taker_bps: 5 }));
+        assert_eq!(s.tier_for(1_000_000).0, 1);
+        assert_eq!(s.tier_for(50_000_000), (2, FeeRates { maker_bps: -1, taker_bps: 3 }));
+    }
+
+    #[test]
+    fn test_schedule_validation() {
+        assert!(FeeSchedule { tiers: vec![] }.validate().is_err());
+        assert!(FeeSchedule { tiers: vec![tier(5, 2, 5)] }.validate().is_err());
+        assert!(FeeSchedule { tiers: vec![tier(0, 2, 5), tier(0, 1, 4)] }.validate().is_err());
+        assert!(FeeSchedule::flat(-6, 5).validate().is_err());
+        assert!(FeeSchedule::flat(2, -1).validate().is_err());
+        assert!(FeeSchedule::flat(-5, 5).validate().is_ok());
+    }
+
+    #[test]
+    fn test_volume_rolls_off_after_window() {
+        let mut w = VolumeWindow::default();
+        w.record("a", 0, 100);
+        w.record("a", DAY_SECS / 2, 50);
+        w.record("a", 10 * DAY_SECS, 10);
+        assert_eq!(w.volume("a", 10 * DAY_SECS), 160);
+        assert_eq!(w.volume("a", 30 * DAY_SECS), 10);
+        w.record("a", 31 * DAY_SECS, 1);
+        assert_eq!(w.0["a"].len(), 2);
+        assert_eq!(w.volume("b", 0), 0);
+    }
+}
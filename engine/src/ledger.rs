@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::Account;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerKind { Deposit, Withdrawal, Fee, RealizedPnl, LiquidationPenalty, Settlement, Transfer, InsurancePayout, Conversion }
+
+/// One change to a trader's collateral. `amount` is signed (fees and
+/// withdrawals are negative); `balance_after` is the balance of that asset
+/// once it is applied.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LedgerEntry {
+    pub seq: u64, // per trader, from 1 with no gaps
+    pub trader: String,
+    pub ts: u64,
+    pub kind: LedgerKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>, // `None` for the settlement currency (`Account.collateral`)
+    pub amount: i128,
+    pub balance_after: i128,
+    pub reference: Option<String>, // what caused it, e.g. "trade:12" or "order:7"
+}
+
+/// Append-only journal of collateral changes per trader. Balances are only
+/// changed through `post`/`post_asset`, so each always equals the sum of the
+/// trader's entries in that asset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Ledger(BTreeMap<String, Vec<LedgerEntry>>);
+
+impl Ledger {
+    /// Apply `amount` to `account.collateral` and journal it. Zero amounts
+    /// change nothing and are not recorded.
+    pub fn post(&mut self, trader: &str, account: &mut Account, ts: u64, kind: LedgerKind, amount: i128, reference: Option<String>) -> Option<LedgerEntry> {
+        self.post_to(trader, None, &mut account.collateral, ts, kind, amount, reference)
+    }
+
+    /// Same as `post`, for a balance held in a collateral asset other than
+    /// the settlement currency.
+    #[allow(clippy::too_many_arguments)]
+    pub fn post_asset(&mut self, trader: &str, asset: &str, balance: &mut i128, ts: u64, kind: LedgerKind, amount: i128, reference: Option<String>) -> Option<LedgerEntry> {
+        self.post_to(trader, Some(asset.to_string()), balance, ts, kind, amount, reference)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn post_to(&mut self, trader: &str, asset: Option<String>, balance: &mut i128, ts: u64, kind: LedgerKind, amount: i128, reference: Option<String>) -> Option<LedgerEntry> {
+        if amount == 0 { return None; }
+        *balance += amount;
+        let entries = self.0.entry(trader.to_string()).or_default();
+        let entry = LedgerEntry { seq: entries.len() as u64 + 1, trader: trader.to_string(), ts, kind, asset, amount, balance_after: *balance, reference };
+        entries.push(entry.clone());
+        Some(entry)
+    }
+
+    pub fn entries(&self, trader: &str) -> &[LedgerEntry] {
+        self.0.get(trader).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Up to `limit` entries after seq `after`, oldest first.
+    pub fn page(&self, trader: &str, after: u64, limit: usize) -> &[LedgerEntry] {
+        let entries = self.entries(trader);
+        let start = (after as usize).min(entries.len());
+        &entries[start..start.saturating_add(limit).min(entries.len())]
+    }
+
+    /// Sum of a trader's entries in the settlement currency.
+    pub fn balance(&self, trader: &str) -> i128 {
+        self.asset_balance(trader, None)
+    }
+
+    /// Sum of a trader's entries in `asset` (`None` for the settlement currency).
+    pub fn asset_balance(&self, trader: &str, asset: Option<&str>) -> i128 {
+        self.entries(trader).iter().filter(|e| e.asset.as_deref() == asset).map(|e| e.amount).sum()
+    }
+
+    pub fn traders(&self) -> impl Iterator<Item = &String> {
+        self.0.keys()
+    }
+}
+
+/// Where trading fees go. `balance` is what can still be swept; it always
+/// equals `accrued - swept - absorbed`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Treasury {
+    pub balance: i128,
+    pub accrued: i128, // all fees ever charged, net of rebates
+    pub swept: i128,
+    #[serde(default)]
+    pub absorbed: i128, // paid out to cover account deficits
+}
+
+impl Treasury {
+    pub fn accrue(&mut self, amount: i128) {
+        self.balance += amount;
+        self.accrued += amount;
+    }
+
+    /// Take `amount` (everything if `None`) out; `None` if that is more than
+    /// the balance or nothing at all.
+    pub fn sweep(&mut self, amount: Option<i128>) -> Option<i128> {
+        let amount = amount.unwrap_or(self.balance);
+        if amount <= 0 || amount > self.balance { return None; }
+        self.balance -= amount;
+        self.swept += amount;
+        Some(amount)
+    }
+
+    /// Cover up to `shortfall` out of the balance; returns what was covered.
+    pub fn absorb(&mut self, shortfall: i128) -> i128 {
+        let amount = shortfall.clamp(0, self.balance.max(0));
+        self.balance -= amount;
+        self.absorbed += amount;
+        amount
+    }
+}
+
+/// A liquidation that left an account's settlement balance below zero after
+/// its other collateral assets were converted to cover it. The balance is
+/// reset to zero (an `insurance_payout` entry for `shortfall`),
+/// the treasury covers what it can and the rest stays `uncovered`. The
+/// account cannot trade, withdraw or transfer out until it is resolved.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Deficit {
+    pub id: u64, // from 1, in the order they happened
+    pub trader: String,
+    pub ts: u64,
+    pub mark: i128, // mark the position was liquidated at
+    pub shortfall: i128,
+    pub absorbed: i128, // covered by the fee treasury
+    pub uncovered: i128, // `shortfall - absorbed`, a loss to the exchange
+    pub resolved_ts: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_and_page() {
+        let mut ledger = Ledger::default();
+        let mut acc = Account { collateral: 0, locked_margin: 0 };
+        ledger.post("a", &mut acc, 1, LedgerKind::Deposit, 1_000, None);
+        assert!(ledger.post("a", &mut acc, 2, LedgerKind::Fee, 0, Some("trade:1".into())).is_none());
+        ledger.post("a", &mut acc, 2, LedgerKind::Fee, -5, Some("trade:1".into()));
+        let last = ledger.post("a", &mut acc, 3, LedgerKind::Withdrawal, -100, None).unwrap();
+        assert_eq!((last.seq, last.balance_after), (3, 895));
+        assert_eq!(acc.collateral, ledger.balance("a"));
+        let page: Vec<u64> = ledger.page("a", 1, 1).iter().map(|e| e.seq).collect();
+        assert_eq!(page, vec![2]);
+        assert!(ledger.page("a", 3, 10).is_empty());
+        assert!(ledger.page("b", 0, 10).is_empty());
+        // other assets share the seq but not the balance
+        let mut eth = 0;
+        let e = ledger.post_asset("a", "ETH", &mut eth, 4, LedgerKind::Deposit, 2, None).unwrap();
+        assert_eq!((e.seq, e.asset.as_deref(), e.balance_after), (4, Some("ETH"), 2));
+        assert_eq!((ledger.balance("a"), ledger.asset_balance("a", Some("ETH"))), (895, 2));
+    }
+
+    #[test]
+    fn test_treasury_sweep() {
+        let mut t = Treasury::default();
+        t.accrue(7);
+        assert_eq!(t.sweep(Some(8)), None);
+        assert_eq!(t.sweep(Some(3)), Some(3));
+        assert_eq!(t.sweep(None), Some(4));
+        assert_eq!(t.sweep(None), None);
+        assert_eq!((t.balance, t.accrued, t.swept), (0, 7, 7));
+    }
+
+    #[test]
+    fn test_treasury_absorb() {
+        let mut t = Treasury::default();
+        t.accrue(10);
+        assert_eq!(t.absorb(4), 4);
+        assert_eq!(t.absorb(9), 6);
+        assert_eq!(t.absorb(1), 0);
+        assert_eq!((t.balance, t.accrued, t.absorbed), (0, 10, 10));
+    }
+}
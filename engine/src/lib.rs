@@ -1,5 +1,23 @@
 pub mod types;
 pub mod risk;
+pub mod book;
+pub mod orders;
+pub mod candles;
+pub mod trades;
+pub mod machine;
+pub mod clock;
+pub mod ledger;
+pub mod fees;
+pub mod collateral;
 
 pub use risk::*;
 pub use types::*;
+pub use book::{OrderBook, Cancelled, Level};
+pub use orders::{OrderRecord, OrderStatus};
+pub use candles::{Candle, Candles, Interval};
+pub use trades::{Fill, Liquidity, TradeExecution};
+pub use ledger::{Deficit, Ledger, LedgerEntry, LedgerKind, Treasury};
+pub use collateral::{CollateralAsset, PriceSource, SETTLEMENT_ASSET};
+pub use fees::{FeeRates, FeeSchedule, FeeTier, VolumeWindow, VOLUME_WINDOW_DAYS};
+pub use clock::{AcceleratedClock, Clock, ManualClock, SystemClock};
+pub use machine::{apply, BookAction, CancelReason, EngineCommand, EngineConfig, EngineEvent, EngineState, LogEntry, Match, MatchFees, NewOrder, OracleSource, Refusal, Snapshot};
@@ -0,0 +1,1341 @@
+use std::collections::{BTreeMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use crate::{Account, Cancelled, CollateralAsset, Deficit, FeeRates, FeeSchedule, Ledger, LedgerEntry, LedgerKind, Treasury, OraclePrice, Order, OrderBook, OrderRecord, Position, PreTrade, PriceSource, RejectReason, RiskLimits, Side, StpMode, TradeExecution, VolumeWindow, SETTLEMENT_ASSET};
+
+/// Exchange core as a state machine: `apply(state, command, now)` gives the
+/// next state and the events it produced. Nothing in here reads the clock or
+/// a random source, and every map iterates in key order, so the same command
+/// log always gives the same events and state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineState {
+    pub cfg: EngineConfig,
+    pub now: u64, // unix secs of the last command applied
+    pub book: OrderBook,
+    pub accounts: BTreeMap<String, Account>,
+    #[serde(default)]
+    pub masters: BTreeMap<String, String>, // sub-account -> its master
+    #[serde(default)]
+    pub collateral_assets: BTreeMap<String, CollateralAsset>, // accepted besides the settlement currency
+    #[serde(default)]
+    pub asset_balances: BTreeMap<String, BTreeMap<String, i128>>, // trader -> asset -> amount, other than the settlement currency
+    #[serde(default)]
+    pub ledger: Ledger, // every change to `Account.collateral`, per trader
+    #[serde(default)]
+    pub treasury: Treasury, // fees charged on matches
+    #[serde(default)]
+    pub deficits: Vec<Deficit>, // liquidations that left an account below zero, oldest first
+    pub positions: BTreeMap<String, Position>,
+    pub oracle: OraclePrice, // single-product demo
+    #[serde(default)]
+    pub oracle_source: Option<OracleSource>, // who set `oracle` last; None until it is first set
+    #[serde(default)]
+    pub fees: FeeSchedule, // tiers by 30-day traded notional
+    #[serde(default)]
+    pub fee_overrides: BTreeMap<String, FeeRates>, // per-account rates that replace the tier
+    #[serde(default)]
+    pub volume_30d: VolumeWindow, // traded notional per trader, for tiering
+    #[serde(default)]
+    pub risk_limits: RiskLimits, // pre-trade checks
+    #[serde(default)]
+    pub order_times: BTreeMap<String, VecDeque<u64>>, // when recent orders were accepted, for the rate limit
+    pub nonces: BTreeMap<String, u64>, // for signing demo
+    pub stp_defaults: BTreeMap<String, StpMode>, // per-account self-trade prevention mode
+    pub last_order_id: u64, // ids are handed out once and never reused
+    pub client_ids: BTreeMap<String, BTreeMap<String, (u64, u64)>>, // trader -> client_order_id -> (order id, first seen); pruned on tick
+    pub orders: BTreeMap<u64, OrderRecord>, // status/history by exchange id
+    pub trades: VecDeque<TradeExecution>, // ascending trade id
+    pub last_trade_id: u64,
+    pub traded_volume: i128, // cumulative matched qty
+    pub last_price: Option<i128>, // price of the most recent trade
+    pub last_mark: Option<i128>, // mark seen by the last tick; a change triggers the liquidation sweep
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EngineConfig {
+    pub client_id_window_secs: u64, // retries with the same client_order_id inside this window return the original order
+    pub order_retention_secs: u64, // closed orders stay queryable this long
+    pub trade_retention: usize, // most recent trades kept
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self { client_id_window_secs: 60, order_retention_secs: 86_400, trade_retention: 100_000 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleSource { Feed, Admin }
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason { Requested, SelfTrade, Expired, Deficit }
+
+/// What happened to a resting order: `add` joins the back of the queue,
+/// `update` changes its size in place, `refill` shows the next iceberg slice
+/// and moves it to the back, `remove` takes it off the book.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BookAction { Add, Update, Refill, Remove }
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NewOrder {
+    pub trader: String,
+    pub side: Side,
+    pub price: i128,
+    pub qty: i128,
+    pub leverage: u32,
+    pub ttl_secs: u64,
+    pub is_limit: bool,
+    pub display_qty: Option<i128>, // iceberg: show at most this much at a time
+    pub stp_mode: Option<StpMode>, // falls back to the account default
+    pub client_order_id: Option<String>,
+    pub onchain: Option<(u64, String)>, // (id, tx) if the order was placed on-chain first; kept as `Order.onchain_id`
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EngineCommand {
+    PlaceOrder(NewOrder),
+    CancelOrder { trader: String, id: Option<u64>, client_order_id: Option<String> },
+    /// `asset` is the settlement currency if `None`.
+    Deposit { trader: String, amount: i128, #[serde(default)] asset: Option<String> },
+    Withdraw { trader: String, amount: i128, #[serde(default)] asset: Option<String> },
+    /// Accept `asset` as collateral, or change its haircut or price source.
+    SetCollateralAsset { asset: String, config: CollateralAsset },
+    /// New price for an asset whose source is `Feed`.
+    SetCollateralPrice { asset: String, price: i128 },
+    SetOracle { price: i128, source: OracleSource },
+    /// Flat rates for everyone: a one-tier schedule.
+    SetFees { maker_bps: i64, taker_bps: i64 },
+    SetFeeSchedule(FeeSchedule),
+    /// Rates for one trader regardless of volume; `None` puts them back on the schedule.
+    SetFeeOverride { trader: String, rates: Option<FeeRates> },
+    /// Replace the pre-trade check pipeline and its limits.
+    SetRiskLimits(RiskLimits),
+    SetStpDefault { trader: String, mode: StpMode },
+    ConsumeNonce { trader: String, nonce: u64 },
+    /// Open `sub` under `master`, with its own collateral, margin and liquidation.
+    CreateSubAccount { master: String, sub: String },
+    /// Move free collateral between accounts under the same master (the
+    /// master included).
+    Transfer { from: String, to: String, amount: i128 },
+    /// Take accrued fees out of the treasury; everything if `amount` is `None`.
+    SweepFees { amount: Option<i128> },
+    /// Mark a trader's open deficits resolved and let the account trade again.
+    ResolveDeficit { trader: String },
+    /// Expire orders past their TTL, run the liquidation sweep if the mark
+    /// moved, then match. With `txs`, apply
+    /// exactly that many matches (settled on-chain under those tx hashes)
+    /// instead of everything that crosses.
+    Tick { txs: Option<Vec<Option<String>>> },
+    Configure(EngineConfig),
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EngineEvent {
+    OrderAccepted { id: u64, trader: String, client_order_id: Option<String>, side: Side, price: i128, qty: i128 },
+    /// `reason` is the message; the flattened `code` and its fields say which check failed.
+    OrderRejected {
+        id: u64,
+        trader: String,
+        client_order_id: Option<String>,
+        reason: String,
+        #[serde(flatten)]
+        reject: RejectReason,
+    },
+    /// One side of a trade; `done` once nothing is left resting.
+    OrderFilled { id: u64, trader: String, price: i128, qty: i128, fee: i128, done: bool },
+    OrderCancelled { id: u64, trader: String, client_order_id: Option<String>, cancelled_qty: i128, reason: CancelReason },
+    #[serde(rename = "match")]
+    Trade {
+        #[serde(flatten)]
+        trade: TradeExecution,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tx: Option<String>,
+    },
+    Oracle { price: i128, source: OracleSource },
+    Liquidation { trader: String, mark: i128, ts: u64 },
+    Deficit {
+        #[serde(flatten)]
+        deficit: Deficit,
+    },
+    DeficitResolved { trader: String, ids: Vec<u64> },
+    Deposit {
+        trader: String,
+        amount: i128,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        asset: Option<String>,
+    },
+    Withdrawal {
+        trader: String,
+        amount: i128,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        asset: Option<String>,
+    },
+    CollateralPrice { asset: String, price: i128 },
+    SubAccountCreated { master: String, sub: String },
+    Transfer { from: String, to: String, amount: i128 },
+    SelfTradePrevented { trader: String, buy_id: u64, sell_id: u64, cancelled: Vec<u64> },
+    /// A collateral change journaled in the ledger.
+    Ledger {
+        #[serde(flatten)]
+        entry: LedgerEntry,
+    },
+    /// A resting order changed; `qty` is what is visible after it (iceberg
+    /// reserves are not), 0 once the order left the book. Public, so it names
+    /// no trader.
+    BookChanged { action: BookAction, id: u64, side: Side, price: i128, qty: i128 },
+    /// `amount` left the treasury; `balance` is what remains.
+    FeesSwept { amount: i128, balance: i128 },
+    /// The command was not applied and changed nothing.
+    Refused { refusal: Refusal },
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Refusal {
+    /// Retry of `client_order_id` inside the window; `id` is the original order.
+    Duplicate { id: u64 },
+    /// `client_order_id` is past the window but its order is still resting.
+    ClientOrderIdInUse { id: u64 },
+    OrderNotFound,
+    /// Deposit or withdrawal of zero or less.
+    InvalidAmount,
+    InsufficientCollateral,
+    NonceMismatch { expected: u64 },
+    /// Sweep of more than the treasury holds, or of nothing.
+    InsufficientFees { balance: i128 },
+    InvalidFees { reason: String },
+    InvalidRiskLimits { reason: String },
+    AccountNotFound,
+    AccountExists,
+    UnknownAsset,
+    InvalidCollateral { reason: String },
+    InvalidSubAccount { reason: String },
+    /// Transfer that is not allowed, e.g. across masters.
+    InvalidTransfer { reason: String },
+    /// The account has an unresolved deficit.
+    AccountBlocked,
+    NoOpenDeficit,
+}
+
+/// The orders at the front of the bid and ask queues, priced at their midpoint
+/// but not yet applied. Each side is a FIFO queue, not sorted by price, so
+/// the two prices are not required to cross.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub buy_id: u64,
+    pub sell_id: u64,
+    pub buy_trader: String,
+    pub sell_trader: String,
+    pub buy_leverage: u32,
+    pub sell_leverage: u32,
+    pub price: i128,
+    pub qty: i128,
+    pub maker_side: Side, // the order that was resting first; the other one crossed it
+    pub buy_onchain_id: Option<u64>, // contract ids, for settling the match on-chain
+    pub sell_onchain_id: Option<u64>,
+}
+
+impl Match {
+    fn of(buy_id: u64, buy: &Order, sell_id: u64, sell: &Order) -> Self {
+        // orders from before `seq` existed have 0 and fall back to id order
+        let maker_side = if (buy.seq, buy_id) < (sell.seq, sell_id) { Side::Buy } else { Side::Sell };
+        Match {
+            buy_id,
+            sell_id,
+            maker_side,
+            price: (buy.price + sell.price) / 2,
+            qty: buy.qty.min(sell.qty),
+            buy_trader: buy.trader.clone(),
+            sell_trader: sell.trader.clone(),
+            buy_leverage: buy.leverage,
+            sell_leverage: sell.leverage,
+            buy_onchain_id: buy.onchain_id,
+            sell_onchain_id: sell.onchain_id,
+        }
+    }
+}
+
+/// What each side of a match pays; a negative `maker_fee` is a rebate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MatchFees {
+    pub maker_fee: i128,
+    pub taker_fee: i128,
+    pub maker_bps: i64,
+    pub taker_bps: i64,
+}
+
+/// One line of a command log: the command and the time it was applied at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub ts: u64,
+    pub cmd: EngineCommand,
+}
+
+/// State as of log entry `last_seq`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub last_seq: u64,
+    pub state: EngineState,
+}
+
+/// Apply one command at time `now`.
+pub fn apply(mut state: EngineState, cmd: EngineCommand, now: u64) -> (EngineState, Vec<EngineEvent>) {
+    let events = state.apply(cmd, now);
+    (state, events)
+}
+
+// zero-day futures: a position runs for at most a day from when it was opened
+const POSITION_TTL_SECS: u64 = 86_400;
+
+enum Admission { Reject(RejectReason), Refuse(Refusal), Accept }
+
+impl EngineState {
+    pub fn new(cfg: EngineConfig) -> Self {
+        Self {
+            cfg,
+            now: 0,
+            book: Default::default(),
+            accounts: Default::default(),
+            masters: Default::default(),
+            collateral_assets: Default::default(),
+            asset_balances: Default::default(),
+            ledger: Default::default(),
+            treasury: Default::default(),
+            deficits: Default::default(),
+            positions: Default::default(),
+            oracle: OraclePrice { price: 100, conf: 0, ts: 0 },
+            oracle_source: None,
+            fees: Default::default(),
+            fee_overrides: Default::default(),
+            volume_30d: Default::default(),
+            risk_limits: Default::default(),
+            order_times: Default::default(),
+            nonces: Default::default(),
+            stp_defaults: Default::default(),
+            last_order_id: 0,
+            client_ids: Default::default(),
+            orders: Default::default(),
+            trades: Default::default(),
+            last_trade_id: 0,
+            traded_volume: 0,
+            last_price: None,
+            last_mark: None,
+        }
+    }
+
+    /// In-place form of [`apply`].
+    pub fn apply(&mut self, cmd: EngineCommand, now: u64) -> Vec<EngineEvent> {
+        self.now = now;
+        let mut out = Vec::new();
+        match cmd {
+            EngineCommand::PlaceOrder(o) => self.place(o, &mut out),
+            EngineCommand::CancelOrder { trader, id, client_order_id } => {
+                let id = id.or_else(|| client_order_id.and_then(|cid| self.client_order(&trader, &cid)));
+                if id.and_then(|id| self.cancel_resting(&trader, id, &mut out)).is_none() {
+                    out.push(EngineEvent::Refused { refusal: Refusal::OrderNotFound });
+                }
+            }
+            EngineCommand::Deposit { trader, amount, asset } => {
+                if amount <= 0 { return vec![EngineEvent::Refused { refusal: Refusal::InvalidAmount }]; }
+                let asset = asset.filter(|a| a != SETTLEMENT_ASSET);
+                match &asset {
+                    Some(a) if !self.collateral_assets.contains_key(a) => return vec![EngineEvent::Refused { refusal: Refusal::UnknownAsset }],
+                    Some(a) => self.post_asset(&trader, a, LedgerKind::Deposit, amount, None, &mut out),
+                    None => self.post(&trader, LedgerKind::Deposit, amount, None, &mut out),
+                }
+                out.push(EngineEvent::Deposit { trader, amount, asset });
+            }
+            EngineCommand::Withdraw { trader, amount, asset } => {
+                let asset = asset.filter(|a| a != SETTLEMENT_ASSET);
+                match self.check_withdraw(&trader, asset.as_deref(), amount) {
+                    Err(refusal) => out.push(EngineEvent::Refused { refusal }),
+                    Ok(()) => {
+                        match &asset {
+                            Some(a) => self.post_asset(&trader, a, LedgerKind::Withdrawal, -amount, None, &mut out),
+                            None => self.post(&trader, LedgerKind::Withdrawal, -amount, None, &mut out),
+                        }
+                        out.push(EngineEvent::Withdrawal { trader, amount, asset });
+                    }
+                }
+            }
+            EngineCommand::SetCollateralAsset { asset, mut config } => {
+                if asset == SETTLEMENT_ASSET || asset.is_empty() {
+                    out.push(EngineEvent::Refused { refusal: Refusal::InvalidCollateral { reason: "not a collateral asset name".into() } });
+                } else if let Err(reason) = config.validate() {
+                    out.push(EngineEvent::Refused { refusal: Refusal::InvalidCollateral { reason: reason.into() } });
+                } else {
+                    config.price_ts = self.now;
+                    self.collateral_assets.insert(asset, config);
+                }
+            }
+            EngineCommand::SetCollateralPrice { asset, price } => match self.collateral_assets.get_mut(&asset) {
+                None => out.push(EngineEvent::Refused { refusal: Refusal::UnknownAsset }),
+                Some(c) if c.source != PriceSource::Feed || price < 0 => out.push(EngineEvent::Refused { refusal: Refusal::InvalidCollateral { reason: "price is not fed for this asset".into() } }),
+                Some(c) => {
+                    c.price = price;
+                    c.price_ts = self.now;
+                    out.push(EngineEvent::CollateralPrice { asset, price });
+                }
+            },
+            EngineCommand::SetOracle { price, source } => {
+                self.oracle.price = price;
+                self.oracle.ts = self.now;
+                self.oracle_source = Some(source);
+                out.push(EngineEvent::Oracle { price, source });
+            }
+            EngineCommand::SetFees { maker_bps, taker_bps } => self.set_fee_schedule(FeeSchedule::flat(maker_bps, taker_bps), &mut out),
+            EngineCommand::SetFeeSchedule(schedule) => self.set_fee_schedule(schedule, &mut out),
+            EngineCommand::SetFeeOverride { trader, rates } => match rates.map(|r| r.validate()) {
+                Some(Err(reason)) => out.push(EngineEvent::Refused { refusal: Refusal::InvalidFees { reason: reason.into() } }),
+                _ => match rates {
+                    Some(r) => { self.fee_overrides.insert(trader, r); }
+                    None => { self.fee_overrides.remove(&trader); }
+                },
+            },
+            EngineCommand::SetRiskLimits(limits) => match limits.validate() {
+                Err(reason) => out.push(EngineEvent::Refused { refusal: Refusal::InvalidRiskLimits { reason: reason.into() } }),
+                Ok(()) => self.risk_limits = limits,
+            },
+            EngineCommand::SetStpDefault { trader, mode } => { self.stp_defaults.insert(trader, mode); }
+            EngineCommand::ConsumeNonce { trader, nonce } => {
+                let cur = self.nonces.get(&trader).cloned().unwrap_or(0);
+                if nonce != cur {
+                    out.push(EngineEvent::Refused { refusal: Refusal::NonceMismatch { expected: cur } });
+                } else {
+                    self.nonces.insert(trader, cur + 1);
+                }
+            }
+            EngineCommand::CreateSubAccount { master, sub } => match self.check_sub_account(&master, &sub) {
+                Err(refusal) => out.push(EngineEvent::Refused { refusal }),
+                Ok(()) => {
+                    self.accounts.insert(sub.clone(), Account { collateral: 0, locked_margin: 0 });
+                    self.masters.insert(sub.clone(), master.clone());
+                    out.push(EngineEvent::SubAccountCreated { master, sub });
+                }
+            },
+            EngineCommand::Transfer { from, to, amount } => match self.check_transfer(&from, &to, amount) {
+                Err(refusal) => out.push(EngineEvent::Refused { refusal }),
+                Ok(()) => {
+                    self.post(&from, LedgerKind::Transfer, -amount, Some(format!("transfer:{}", to)), &mut out);
+                    self.post(&to, LedgerKind::Transfer, amount, Some(format!("transfer:{}", from)), &mut out);
+                    out.push(EngineEvent::Transfer { from, to, amount });
+                }
+            },
+            EngineCommand::SweepFees { amount } => match self.treasury.sweep(amount) {
+                Some(amount) => out.push(EngineEvent::FeesSwept { amount, balance: self.treasury.balance }),
+                None => out.push(EngineEvent::Refused { refusal: Refusal::InsufficientFees { balance: self.treasury.balance } }),
+            },
+            EngineCommand::ResolveDeficit { trader } => {
+                let now = self.now;
+                let ids: Vec<u64> = self.deficits.iter_mut()
+                    .filter(|d| d.trader == trader && d.resolved_ts.is_none())
+                    .map(|d| { d.resolved_ts = Some(now); d.id })
+                    .collect();
+                if ids.is_empty() {
+                    out.push(EngineEvent::Refused { refusal: Refusal::NoOpenDeficit });
+                } else {
+                    out.push(EngineEvent::DeficitResolved { trader, ids });
+                }
+            }
+            EngineCommand::Tick { txs } => {
+                self.expire_orders(&mut out);
+                self.prune_orders();
+                self.prune_client_ids();
+                self.sweep(&mut out);
+                match txs {
+                    None => while let Some(m) = self.next_match(&mut out) { self.apply_match(m, None, &mut out); },
+                    Some(txs) => {
+                        for tx in txs {
+                            let Some(m) = self.next_match(&mut out) else { break };
+                            self.apply_match(m, tx, &mut out);
+                        }
+                        // leave the next pair free of self-trades so `peek_match` sees it
+                        let _ = self.next_match(&mut out);
+                    }
+                }
+            }
+            EngineCommand::Configure(cfg) => self.cfg = cfg,
+        }
+        out
+    }
+
+    /// Order id a trader's `client_order_id` maps to.
+    pub fn client_order(&self, trader: &str, client_order_id: &str) -> Option<u64> {
+        self.client_ids.get(trader)?.get(client_order_id).map(|(id, _)| *id)
+    }
+
+    /// The master an account rolls up to: itself unless it is a sub-account.
+    pub fn master_of<'a>(&'a self, trader: &'a str) -> &'a str {
+        self.masters.get(trader).map(String::as_str).unwrap_or(trader)
+    }
+
+    /// Sub-accounts of `master`, in name order.
+    pub fn subs_of<'a>(&'a self, master: &'a str) -> impl Iterator<Item = &'a String> {
+        self.masters.iter().filter(move |(_, m)| m.as_str() == master).map(|(s, _)| s)
+    }
+
+    /// Settlement balance plus every other collateral asset at its price
+    /// after the haircut; what equity and health are computed from.
+    pub fn collateral_value(&self, trader: &str) -> i128 {
+        let settlement = self.accounts.get(trader).map(|a| a.collateral).unwrap_or(0);
+        let others: i128 = self.asset_balances.get(trader).into_iter().flatten()
+            .filter_map(|(asset, amount)| self.collateral_assets.get(asset).map(|c| c.value(*amount, self.oracle.price)))
+            .sum();
+        settlement + others
+    }
+
+    /// Collateral value not locked as margin, less any unrealized loss at
+    /// the current mark; what can be withdrawn or transferred out.
+    pub fn free_collateral(&self, trader: &str) -> i128 {
+        let Some(acc) = self.accounts.get(trader) else { return 0 };
+        let pnl = self.positions.get(trader).map(|p| (self.oracle.price - p.entry_price) * p.qty).unwrap_or(0);
+        self.collateral_value(trader) - acc.locked_margin + pnl.min(0)
+    }
+
+    /// Whether the trader has a deficit an admin has not resolved yet.
+    pub fn is_blocked(&self, trader: &str) -> bool {
+        self.deficits.iter().any(|d| d.trader == trader && d.resolved_ts.is_none())
+    }
+
+    /// Balance of `asset` (`None` for the settlement currency).
+    pub fn asset_balance(&self, trader: &str, asset: Option<&str>) -> i128 {
+        match asset {
+            Some(a) => self.asset_balances.get(trader).and_then(|b| b.get(a)).copied().unwrap_or(0),
+            None => self.accounts.get(trader).map(|a| a.collateral).unwrap_or(0),
+        }
+    }
+
+    /// A trader's 30-day volume, their tier (`None` if overridden) and the
+    /// rates they pay right now.
+    pub fn fee_rates(&self, trader: &str) -> (i128, Option<usize>, FeeRates) {
+        let volume = self.volume_30d.volume(trader, self.now);
+        match self.fee_overrides.get(trader) {
+            Some(r) => (volume, None, *r),
+            None => { let (tier, r) = self.fees.tier_for(volume); (volume, Some(tier), r) }
+        }
+    }
+
+    /// Whether `PlaceOrder(o)` at `now` would add an order to the book
+    /// (rather than reject it or answer a retry). Lets a caller do outside
+    /// work, such as placing the order on-chain, only for real placements.
+    pub fn would_place(&self, o: &NewOrder, now: u64) -> bool {
+        matches!(self.admission(o, now), Admission::Accept)
+    }
+
+    /// Fees `m` would be charged if applied now. Each side pays its own rate,
+    /// from volume before the trade. A fee never takes more than its payer's
+    /// collateral is worth, other assets included, so it can leave the
+    /// settlement balance negative; a rebate never takes more than the
+    /// treasury holds after the taker fee. The contract caps both the same way
+    /// against what it holds.
+    pub fn match_fees(&self, m: &Match) -> MatchFees {
+        let (maker, taker) = if m.maker_side == Side::Buy { (&m.buy_trader, &m.sell_trader) } else { (&m.sell_trader, &m.buy_trader) };
+        let maker_bps = self.fee_rates(maker).2.maker_bps;
+        let taker_bps = self.fee_rates(taker).2.taker_bps;
+        let notional = m.price.abs() * m.qty.abs();
+        let balance = |t: &str| self.collateral_value(t).max(0);
+        let taker_fee = (notional * taker_bps as i128 / 10_000).min(balance(taker));
+        let maker_fee = (notional * maker_bps as i128 / 10_000).min(balance(maker)).max(-(self.treasury.balance + taker_fee));
+        MatchFees { maker_fee, taker_fee, maker_bps, taker_bps }
+    }
+
+    /// The front pair the next match would apply, if it is not a self-trade.
+    pub fn peek_match(&self) -> Option<Match> {
+        let (buy_id, buy) = self.book.best_buy()?;
+        let (sell_id, sell) = self.book.best_sell()?;
+        if buy.trader == sell.trader { return None; }
+        Some(Match::of(*buy_id, buy, *sell_id, sell))
+    }
+
+    fn admission(&self, o: &NewOrder, now: u64) -> Admission {
+        if self.is_blocked(&o.trader) { return Admission::Reject(RejectReason::AccountBlocked); }
+        if let Some(d) = o.display_qty {
+            if d <= 0 || d > o.qty { return Admission::Reject(RejectReason::InvalidDisplayQty); }
+        }
+        if let Some(cid) = &o.client_order_id {
+            if let Some((prev, seen)) = self.client_ids.get(&o.trader).and_then(|m| m.get(cid)).copied() {
+                if now.saturating_sub(seen) < self.cfg.client_id_window_secs {
+                    return Admission::Refuse(Refusal::Duplicate { id: prev });
+                }
+                if self.book.get(prev).is_some() {
+                    return Admission::Refuse(Refusal::ClientOrderIdInUse { id: prev });
+                }
+            }
+        }
+        // retries are answered above, so they never count against the limits
+        match self.risk_limits.check(o, &self.pre_trade(&o.trader, now)) {
+            Err(reason) => Admission::Reject(reason),
+            Ok(()) => Admission::Accept,
+        }
+    }
+
+    fn pre_trade(&self, trader: &str, now: u64) -> PreTrade {
+        let window_start = now.saturating_sub(self.risk_limits.rate_window_secs);
+        PreTrade {
+            account_exists: self.accounts.contains_key(trader),
+            free_collateral: self.free_collateral(trader),
+            mark: self.oracle.price,
+            open_orders: self.book.buys.iter().chain(self.book.sells.iter()).filter(|(_, o)| o.trader == trader).count(),
+            recent_orders: self.order_times.get(trader).map(|t| t.iter().filter(|ts| **ts > window_start).count()).unwrap_or(0),
+        }
+    }
+
+    // change a trader's collateral through the ledger, opening the account if needed
+    fn post(&mut self, trader: &str, kind: LedgerKind, amount: i128, reference: Option<String>, out: &mut Vec<EngineEvent>) {
+        let acc = self.accounts.entry(trader.to_string()).or_insert(Account{ collateral: 0, locked_margin: 0 });
+        if let Some(entry) = self.ledger.post(trader, acc, self.now, kind, amount, reference) {
+            out.push(EngineEvent::Ledger { entry });
+        }
+    }
+
+    fn check_sub_account(&self, master: &str, sub: &str) -> Result<(), Refusal> {
+        if !self.accounts.contains_key(master) { return Err(Refusal::AccountNotFound); }
+        if self.masters.contains_key(master) { return Err(Refusal::InvalidSubAccount { reason: "a sub-account cannot have sub-accounts".into() }); }
+        if sub.is_empty() { return Err(Refusal::InvalidSubAccount { reason: "sub-account name required".into() }); }
+        if self.accounts.contains_key(sub) { return Err(Refusal::AccountExists); }
+        Ok(())
+    }
+
+    // the asset itself must cover the amount, and what it counted for must be free
+    fn check_withdraw(&self, trader: &str, asset: Option<&str>, amount: i128) -> Result<(), Refusal> {
+        if amount <= 0 { return Err(Refusal::InvalidAmount); }
+        if !self.accounts.contains_key(trader) { return Err(Refusal::InsufficientCollateral); }
+        if self.is_blocked(trader) { return Err(Refusal::AccountBlocked); }
+        let value = match asset {
+            Some(a) => self.collateral_assets.get(a).ok_or(Refusal::UnknownAsset)?.value(amount, self.oracle.price),
+            None => amount,
+        };
+        if self.asset_balance(trader, asset) < amount || self.free_collateral(trader) < value { return Err(Refusal::InsufficientCollateral); }
+        Ok(())
+    }
+
+    fn check_transfer(&self, from: &str, to: &str, amount: i128) -> Result<(), Refusal> {
+        if amount <= 0 { return Err(Refusal::InvalidTransfer { reason: "amount must be positive".into() }); }
+        if from == to { return Err(Refusal::InvalidTransfer { reason: "from and to are the same account".into() }); }
+        if !self.accounts.contains_key(from) || !self.accounts.contains_key(to) { return Err(Refusal::AccountNotFound); }
+        if self.master_of(from) != self.master_of(to) { return Err(Refusal::InvalidTransfer { reason: "accounts are not under the same master".into() }); }
+        if self.is_blocked(from) { return Err(Refusal::AccountBlocked); }
+        // transfers move the settlement currency, so other assets only count towards free collateral
+        if self.free_collateral(from) < amount || self.asset_balance(from, None) < amount { return Err(Refusal::InsufficientCollateral); }
+        Ok(())
+    }
+
+    // same as `post` for a non-settlement collateral asset
+    fn post_asset(&mut self, trader: &str, asset: &str, kind: LedgerKind, amount: i128, reference: Option<String>, out: &mut Vec<EngineEvent>) {
+        self.accounts.entry(trader.to_string()).or_insert(Account{ collateral: 0, locked_margin: 0 });
+        let balance = self.asset_balances.entry(trader.to_string()).or_default().entry(asset.to_string()).or_insert(0);
+        if let Some(entry) = self.ledger.post_asset(trader, asset, balance, self.now, kind, amount, reference) {
+            out.push(EngineEvent::Ledger { entry });
+        }
+    }
+
+    fn set_fee_schedule(&mut self, schedule: FeeSchedule, out: &mut Vec<EngineEvent>) {
+        match schedule.validate() {
+            Ok(()) => self.fees = schedule,
+            Err(reason) => out.push(EngineEvent::Refused { refusal: Refusal::InvalidFees { reason: reason.into() } }),
+        }
+    }
+
+    fn next_order_id(&mut self) -> u64 { self.last_order_id += 1; self.last_order_id }
+
+    // validate, dedupe, lock margin and put the order in the book; every order,
+    // on-chain or not, is booked under an id from `next_order_id`
+    fn place(&mut self, o: NewOrder, out: &mut Vec<EngineEvent>) {
+        let now = self.now;
+        let exp = now.saturating_add(o.ttl_secs);
+        let trader = o.trader.clone();
+        let stp = o.stp_mode.or_else(|| self.stp_defaults.get(&trader).copied()).unwrap_or_default();
+        let mut order = Order { trader: trader.clone(), side: o.side, price: o.price, qty: o.qty, leverage: o.leverage, ts: now, expiry_ts: exp, is_limit: o.is_limit, iceberg: None, stp, client_order_id: o.client_order_id.clone(), seq: 0, onchain_id: o.onchain.as_ref().map(|(id, _)| *id) };
+        match self.admission(&o, self.now) {
+            Admission::Accept => {}
+            Admission::Refuse(refusal) => return out.push(EngineEvent::Refused { refusal }),
+            Admission::Reject(reason) => {
+                // rejected orders still get an id so their status can be looked up
+                let id = self.next_order_id();
+                self.orders.insert(id, OrderRecord::rejected(id, &order, reason.to_string(), self.now));
+                return out.push(EngineEvent::OrderRejected { id, trader, client_order_id: order.client_order_id, reason: reason.to_string(), reject: reason });
+            }
+        }
+        if let Some(d) = o.display_qty { order = order.with_display(d); }
+        let id = self.next_order_id();
+        order.seq = id;
+        if let Some(cid) = &o.client_order_id {
+            self.client_ids.entry(trader.clone()).or_default().insert(cid.clone(), (id, self.now));
+        }
+        let window_start = now.saturating_sub(self.risk_limits.rate_window_secs);
+        let times = self.order_times.entry(trader.clone()).or_default();
+        while times.front().map(|ts| *ts <= window_start).unwrap_or(false) { times.pop_front(); }
+        times.push_back(now);
+        // lock margin for this order (simple: notional/leverage); icebergs lock on the full qty including the hidden reserve
+        let notional = o.price.abs() * o.qty.abs();
+        let margin = if o.leverage == 0 { notional } else { notional / (o.leverage as i128) };
+        self.accounts.entry(trader.clone())
+            .and_modify(|a| a.locked_margin += margin)
+            .or_insert(Account{ collateral: 0, locked_margin: margin });
+        self.prune_orders();
+        self.orders.insert(id, OrderRecord::new(id, &order, self.now));
+        out.push(EngineEvent::OrderAccepted { id, trader, client_order_id: order.client_order_id.clone(), side: order.side, price: order.price, qty: order.qty });
+        out.push(EngineEvent::BookChanged { action: BookAction::Add, id, side: order.side, price: order.price, qty: order.qty });
+        self.book.push(id, order);
+    }
+
+    // pull a trader's resting order off the book, release its margin and close its record
+    fn cancel_resting(&mut self, trader: &str, id: u64, out: &mut Vec<EngineEvent>) -> Option<Cancelled> {
+        if self.book.get(id).map(|o| o.trader != trader).unwrap_or(true) { return None; }
+        let c = self.book.cancel(id)?;
+        self.release_order_margin(&c);
+        if let Some(r) = self.orders.get_mut(&c.id) { r.cancel(self.now); }
+        out.push(EngineEvent::OrderCancelled { id: c.id, trader: trader.to_string(), client_order_id: c.order.client_order_id.clone(), cancelled_qty: c.qty, reason: CancelReason::Requested });
+        self.book_changed(c.id, &c.order, false, out);
+        Some(c)
+    }
+
+    // public record of a change to resting order `id`, which looked like
+    // `before`; `refilled` when its visible slice was used up and replaced
+    fn book_changed(&self, id: u64, before: &Order, refilled: bool, out: &mut Vec<EngineEvent>) {
+        let (action, qty) = match self.book.get(id) {
+            None => (BookAction::Remove, 0),
+            Some(o) if refilled => (BookAction::Refill, o.qty),
+            Some(o) if o.qty != before.qty => (BookAction::Update, o.qty),
+            // only the hidden reserve changed
+            Some(_) => return,
+        };
+        out.push(EngineEvent::BookChanged { action, id, side: before.side, price: before.price, qty });
+    }
+
+    // release the margin place locked for the cancelled quantity
+    fn release_order_margin(&mut self, c: &Cancelled) {
+        let margin = crate::required_margin(c.qty, c.order.price, c.order.leverage);
+        if let Some(a) = self.accounts.get_mut(&c.order.trader) { a.locked_margin = (a.locked_margin - margin).max(0); }
+    }
+
+    // a fill releases the margin its order locked for `qty` and locks the
+    // position's margin at its new size instead, so a flat position holds none
+    fn move_fill_margin(&mut self, trader: &str, order: Option<&Order>, qty: i128) {
+        let released = order.map(|o| crate::required_margin(qty, o.price, o.leverage)).unwrap_or(0);
+        let added = match self.positions.get_mut(trader) {
+            Some(p) => {
+                let margin = crate::required_margin(p.qty, p.entry_price, p.leverage);
+                margin - std::mem::replace(&mut p.margin, margin)
+            }
+            None => 0,
+        };
+        if let Some(a) = self.accounts.get_mut(trader) { a.locked_margin = (a.locked_margin - released).max(0) + added; }
+    }
+
+    // drop closed orders older than the retention period
+    fn prune_orders(&mut self) {
+        let (now, retention) = (self.now, self.cfg.order_retention_secs);
+        self.orders.retain(|_, r| r.closed_ts.map(|t| t.saturating_add(retention) > now).unwrap_or(true));
+    }
+
+    // forget client order ids past the window once their order is gone from `orders`
+    // (which outlives the book by the retention period), so lookups keep working until then
+    fn prune_client_ids(&mut self) {
+        let (now, window, orders) = (self.now, self.cfg.client_id_window_secs, &self.orders);
+        self.client_ids.retain(|_, ids| {
+            ids.retain(|_, (id, seen)| now.saturating_sub(*seen) < window || orders.contains_key(id));
+            !ids.is_empty()
+        });
+    }
+
+    // pull resting orders whose TTL has passed and release their margin
+    fn expire_orders(&mut self, out: &mut Vec<EngineEvent>) {
+        for id in self.book.expired(self.now) {
+            let Some(c) = self.book.cancel(id) else { continue };
+            self.release_order_margin(&c);
+            if let Some(r) = self.orders.get_mut(&id) { r.expire(self.now); }
+            out.push(EngineEvent::OrderCancelled { id, trader: c.order.trader.clone(), client_order_id: c.order.client_order_id.clone(), cancelled_qty: c.qty, reason: CancelReason::Expired });
+            self.book_changed(id, &c.order, false, out);
+        }
+    }
+
+    // run the liquidation sweep if the mark moved since the last tick
+    fn sweep(&mut self, out: &mut Vec<EngineEvent>) {
+        let current_mark = self.oracle.price;
+        if self.last_mark != Some(current_mark) {
+            self.last_mark = Some(current_mark);
+            // every oracle tick re-checks every open position
+            let traders: Vec<String> = self.positions.keys().cloned().collect();
+            self.liquidate(traders, current_mark, out);
+        }
+    }
+
+    // Next pair to match. Self-trades are resolved on the way (see
+    // `OrderBook::prevent_self_trade`) and never returned.
+    fn next_match(&mut self, out: &mut Vec<EngineEvent>) -> Option<Match> {
+        loop {
+            let (buy_id, buy) = self.book.best_buy().cloned()?;
+            let (sell_id, sell) = self.book.best_sell().cloned()?;
+            // self-trade prevention: never match a trader against themselves
+            if buy.trader == sell.trader {
+                let cancelled = self.book.prevent_self_trade(buy_id, sell_id);
+                if cancelled.is_empty() { return None; }
+                for c in &cancelled {
+                    self.release_order_margin(c);
+                    if self.book.get(c.id).is_none() {
+                        if let Some(r) = self.orders.get_mut(&c.id) { r.cancel(self.now); }
+                    }
+                    out.push(EngineEvent::OrderCancelled { id: c.id, trader: c.order.trader.clone(), client_order_id: c.order.client_order_id.clone(), cancelled_qty: c.qty, reason: CancelReason::SelfTrade });
+                    self.book_changed(c.id, &c.order, false, out);
+                }
+                let ids: Vec<u64> = cancelled.iter().map(|c| c.id).collect();
+                out.push(EngineEvent::SelfTradePrevented { trader: buy.trader, buy_id, sell_id, cancelled: ids });
+                continue;
+            }
+            return Some(Match::of(buy_id, &buy, sell_id, &sell));
+        }
+    }
+
+    // book a match: fees, positions, book fill, order records, trade, liquidation check
+    fn apply_match(&mut self, m: Match, tx: Option<String>, out: &mut Vec<EngineEvent>) {
+        let Match { buy_id, sell_id, price, qty, .. } = m;
+        let MatchFees { maker_fee, taker_fee, maker_bps, taker_bps } = self.match_fees(&m);
+        let notional = price.abs() * qty.abs();
+        self.volume_30d.record(&m.buy_trader, self.now, notional);
+        self.volume_30d.record(&m.sell_trader, self.now, notional);
+        self.last_trade_id += 1;
+        let trade_id = self.last_trade_id;
+        let (buy_fee, sell_fee) = if m.maker_side == Side::Buy { (maker_fee, taker_fee) } else { (taker_fee, maker_fee) };
+        self.post(&m.buy_trader, LedgerKind::Fee, -buy_fee, Some(format!("trade:{}", trade_id)), out);
+        self.post(&m.sell_trader, LedgerKind::Fee, -sell_fee, Some(format!("trade:{}", trade_id)), out);
+        self.treasury.accrue(maker_fee + taker_fee);
+        let now = self.now;
+        // buyer long +qty at price
+        apply_fill(position_at(&mut self.positions, &m.buy_trader, m.buy_leverage, price, now), price, qty);
+        // seller short -qty at price
+        apply_fill(position_at(&mut self.positions, &m.sell_trader, m.sell_leverage, price, now), price, -qty);
+        // fill the book; partially filled orders keep their place, consumed iceberg slices are refilled at the back
+        let before = |book: &OrderBook, id| book.get(id).cloned();
+        let (buy_before, sell_before) = (before(&self.book, buy_id), before(&self.book, sell_id));
+        self.book.fill(Side::Buy, buy_id, qty);
+        self.book.fill(Side::Sell, sell_id, qty);
+        let (buy_done, sell_done) = (self.book.get(buy_id).is_none(), self.book.get(sell_id).is_none());
+        self.move_fill_margin(&m.buy_trader, buy_before.as_ref(), qty);
+        self.move_fill_margin(&m.sell_trader, sell_before.as_ref(), qty);
+        self.traded_volume += qty;
+        self.last_price = Some(price);
+        if let Some(r) = self.orders.get_mut(&buy_id) { r.record_fill(price, qty, buy_fee, buy_done, now); }
+        if let Some(r) = self.orders.get_mut(&sell_id) { r.record_fill(price, qty, sell_fee, sell_done, now); }
+        out.push(EngineEvent::OrderFilled { id: buy_id, trader: m.buy_trader.clone(), price, qty, fee: buy_fee, done: buy_done });
+        out.push(EngineEvent::OrderFilled { id: sell_id, trader: m.sell_trader.clone(), price, qty, fee: sell_fee, done: sell_done });
+        // a fill that used up the visible slice of an order still resting refilled it
+        for (id, before) in [(buy_id, buy_before), (sell_id, sell_before)] {
+            if let Some(o) = before { self.book_changed(id, &o, o.qty <= qty, out); }
+        }
+        let trade = TradeExecution { id: trade_id, ts: now, price, qty, buy_id, sell_id, buy_trader: m.buy_trader.clone(), sell_trader: m.sell_trader.clone(), maker_side: m.maker_side, maker_fee, taker_fee, maker_fee_bps: maker_bps, taker_fee_bps: taker_bps };
+        self.trades.push_back(trade.clone());
+        if self.trades.len() > self.cfg.trade_retention.max(1) { self.trades.pop_front(); }
+        out.push(EngineEvent::Trade { trade, tx });
+        let mark = self.oracle.price;
+        self.liquidate([m.buy_trader, m.sell_trader], mark, out);
+    }
+
+    // simple liquidation check for each trader at the given mark
+    fn liquidate(&mut self, traders: impl IntoIterator<Item = String>, mark: i128, out: &mut Vec<EngineEvent>) {
+        for who in traders {
+            let (qty_w, entry_w) = self.positions.get(&who).map(|p| (p.qty, p.entry_price)).unwrap_or((0, 0));
+            if qty_w == 0 { continue; }
+            let pnl = (mark - entry_w) * qty_w; // here short if qty negative
+            let (collateral, locked) = (self.collateral_value(&who), self.accounts.get(&who).map(|a| a.locked_margin).unwrap_or(0));
+            if locked <= 0 { continue; }
+            let equity = collateral + pnl - locked;
+            let health_bps = (equity * 10_000) / locked;
+            if health_bps < 5_000 { // threshold 50%
+                if let Some(a) = self.accounts.get_mut(&who) { a.locked_margin = 0; }
+                self.post(&who, LedgerKind::RealizedPnl, pnl, Some("liquidation".into()), out);
+                if let Some(p) = self.positions.get_mut(&who) { p.qty = 0; p.margin = 0; }
+                out.push(EngineEvent::Liquidation { trader: who.clone(), mark, ts: self.now });
+                self.settle_deficit(&who, mark, out);
+            }
+        }
+    }
+
+    // after a liquidation: convert other collateral assets at their price to
+    // cover a negative settlement balance, reset what is left to zero, let
+    // the treasury absorb what it can, pull the trader's resting orders and
+    // block the account until an admin resolves it
+    fn settle_deficit(&mut self, trader: &str, mark: i128, out: &mut Vec<EngineEvent>) {
+        self.convert_assets(trader, mark, out);
+        let collateral = self.accounts.get(trader).map(|a| a.collateral).unwrap_or(0);
+        if collateral >= 0 { return; }
+        let (id, shortfall) = (self.deficits.len() as u64 + 1, -collateral);
+        self.post(trader, LedgerKind::InsurancePayout, shortfall, Some(format!("deficit:{}", id)), out);
+        let absorbed = self.treasury.absorb(shortfall);
+        let resting: Vec<u64> = self.orders.values().filter(|r| r.trader == trader && self.book.get(r.id).is_some()).map(|r| r.id).collect();
+        for id in resting {
+            let Some(c) = self.book.cancel(id) else { continue };
+            self.release_order_margin(&c);
+            if let Some(r) = self.orders.get_mut(&id) { r.cancel(self.now); }
+            out.push(EngineEvent::OrderCancelled { id, trader: trader.to_string(), client_order_id: c.order.client_order_id.clone(), cancelled_qty: c.qty, reason: CancelReason::Deficit });
+            self.book_changed(id, &c.order, false, out);
+        }
+        let deficit = Deficit { id, trader: trader.to_string(), ts: self.now, mark, shortfall, absorbed, uncovered: shortfall - absorbed, resolved_ts: None };
+        self.deficits.push(deficit.clone());
+        out.push(EngineEvent::Deficit { deficit });
+    }
+
+    // sell just enough of each asset, in name order, to bring the settlement balance back to zero
+    fn convert_assets(&mut self, trader: &str, mark: i128, out: &mut Vec<EngineEvent>) {
+        let held: Vec<(String, i128)> = self.asset_balances.get(trader).into_iter().flatten().map(|(a, n)| (a.clone(), *n)).collect();
+        for (asset, amount) in held {
+            let shortfall = -self.accounts.get(trader).map(|a| a.collateral).unwrap_or(0);
+            if shortfall <= 0 { break; }
+            let price = self.collateral_assets.get(&asset).map(|c| c.price(mark)).unwrap_or(0);
+            if price <= 0 || amount <= 0 { continue; }
+            let units = ((shortfall + price - 1) / price).min(amount);
+            self.post_asset(trader, &asset, LedgerKind::Conversion, -units, Some("liquidation".into()), out);
+            self.post(trader, LedgerKind::Conversion, units * price, Some(format!("conversion:{}", asset)), out);
+        }
+    }
+}
+
+// a trader's position; one that is new or flat counts as opened at `now`
+fn position_at<'a>(positions: &'a mut BTreeMap<String, Position>, trader: &str, leverage: u32, price: i128, now: u64) -> &'a mut Position {
+    let p = positions.entry(trader.to_string()).or_insert(Position{ trader: trader.to_string(), entry_price: price, qty: 0, leverage, margin: 0, opened_ts: now, expiry_ts: 0 });
+    if p.qty == 0 {
+        p.opened_ts = now;
+        p.expiry_ts = now.saturating_add(POSITION_TTL_SECS);
+    }
+    p
+}
+
+// signed qty: positive adds to a long, negative to a short
+fn apply_fill(p: &mut Position, price: i128, qty: i128) {
+    let new_qty = p.qty + qty;
+    if new_qty == 0 {
+        p.entry_price = 0; // flat position
+        p.qty = 0;
+    } else if p.qty == 0 {
+        p.entry_price = price;
+        p.qty = new_qty;
+    } else {
+        // weighted average price
+        p.entry_price = (p.entry_price * p.qty + price * qty) / new_qty;
+        p.qty = new_qty;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Clock;
+
+    fn order(trader: &str, side: Side, price: i128, qty: i128) -> EngineCommand {
+        EngineCommand::PlaceOrder(NewOrder { trader: trader.into(), side, price, qty, leverage: 10, ttl_secs: 600, is_limit: true, display_qty: None, stp_mode: None, client_order_id: None, onchain: None })
+    }
+
+    fn session() -> Vec<(u64, EngineCommand)> {
+        vec![
+            (1, EngineCommand::Deposit { trader: "alice".into(), amount: 1_000, asset: None }),
+            (1, EngineCommand::Deposit { trader: "bob".into(), amount: 1_000, asset: None }),
+            (2, order("alice", Side::Buy, 101, 50)),
+            (2, order("bob", Side::Sell, 99, 50)),
+            (3, EngineCommand::Tick { txs: None }),
+            (4, EngineCommand::SetOracle { price: 80, source: OracleSource::Feed }),
+            (5, EngineCommand::Tick { txs: None }),
+        ]
+    }
+
+    #[test]
+    fn test_match_then_liquidate() {
+        let mut state = EngineState::new(EngineConfig::default());
+        let mut events = Vec::new();
+        for (now, cmd) in session() { events.extend(state.apply(cmd, now)); }
+        let trade = state.trades.back().unwrap();
+        assert_eq!((trade.price, trade.qty, trade.ts), (100, 50, 3));
+        assert!(state.book.buys.is_empty() && state.book.sells.is_empty());
+        // alice is long 50 from 100; the mark at 80 takes her below half her margin
+        assert!(events.contains(&EngineEvent::Liquidation { trader: "alice".into(), mark: 80, ts: 5 }));
+        assert_eq!(state.positions["alice"].qty, 0);
+        // alice's bid was resting first, so she is the maker; her loss leaves
+        // her 1 short, which is reset to zero and covered by the treasury
+        assert_eq!(state.accounts["alice"].collateral, 0);
+        assert_eq!((state.deficits[0].shortfall, state.deficits[0].absorbed, state.deficits[0].uncovered), (1, 1, 0));
+        for (trader, acc) in &state.accounts { assert_eq!(state.ledger.balance(trader), acc.collateral); }
+        // fees: what the treasury took is what the trades and the ledger say was charged
+        let charged: i128 = state.trades.iter().map(|t| t.maker_fee + t.taker_fee).sum();
+        let journaled: i128 = state.accounts.keys().flat_map(|t| state.ledger.entries(t)).filter(|e| e.kind == LedgerKind::Fee).map(|e| -e.amount).sum();
+        assert_eq!((state.treasury.accrued, charged, journaled), (3, 3, 3));
+        assert_eq!((state.treasury.balance, state.treasury.absorbed), (2, 1));
+        let kinds: Vec<LedgerKind> = state.ledger.entries("alice").iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, vec![LedgerKind::Deposit, LedgerKind::Fee, LedgerKind::RealizedPnl, LedgerKind::InsurancePayout]);
+    }
+
+    #[test]
+    fn test_deficit_blocks_until_resolved() {
+        let mut state = EngineState::new(EngineConfig::default());
+        let mut cmds = session();
+        // a bid that is still resting when alice goes under
+        cmds.insert(5, (4, order("alice", Side::Buy, 70, 1)));
+        let mut events = Vec::new();
+        for (now, cmd) in cmds { events.extend(state.apply(cmd, now)); }
+        assert!(matches!(events.last(), Some(EngineEvent::Deficit { deficit }) if deficit.trader == "alice" && deficit.shortfall == 1));
+        assert!(events.iter().any(|e| matches!(e, EngineEvent::OrderCancelled { reason: CancelReason::Deficit, .. })));
+        assert!(state.book.buys.is_empty());
+        assert!(state.is_blocked("alice") && !state.is_blocked("bob"));
+        let ev = state.apply(order("alice", Side::Buy, 80, 1), 6);
+        assert!(matches!(ev.as_slice(), [EngineEvent::OrderRejected { .. }]));
+        state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 10, asset: None }, 6);
+        let withdraw = EngineCommand::Withdraw { trader: "alice".into(), amount: 10, asset: None };
+        assert_eq!(state.apply(withdraw.clone(), 6), vec![EngineEvent::Refused { refusal: Refusal::AccountBlocked }]);
+        let resolve = EngineCommand::ResolveDeficit { trader: "alice".into() };
+        assert_eq!(state.apply(resolve.clone(), 7), vec![EngineEvent::DeficitResolved { trader: "alice".into(), ids: vec![1] }]);
+        assert_eq!(state.apply(resolve, 7), vec![EngineEvent::Refused { refusal: Refusal::NoOpenDeficit }]);
+        assert!(!state.is_blocked("alice"));
+        assert!(state.apply(withdraw, 8).iter().any(|e| matches!(e, EngineEvent::Withdrawal { .. })));
+    }
+
+    #[test]
+    fn test_other_collateral_covers_a_deficit_first() {
+        // alice is liquidated at 60 with her settlement balance below zero; a full
+        // haircut keeps her ETH out of equity so every run is liquidated alike
+        let run = |eth: i128| {
+            let mut state = EngineState::new(EngineConfig::default());
+            let config = CollateralAsset { haircut_bps: 10_000, source: PriceSource::Feed, price: 0, price_ts: 0 };
+            state.apply(EngineCommand::SetCollateralAsset { asset: "ETH".into(), config }, 1);
+            state.apply(EngineCommand::SetCollateralPrice { asset: "ETH".into(), price: 300 }, 1);
+            if eth > 0 { state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: eth, asset: Some("ETH".into()) }, 1); }
+            for (now, cmd) in session() {
+                let cmd = match cmd { EngineCommand::SetOracle { source, .. } => EngineCommand::SetOracle { price: 60, source }, c => c };
+                state.apply(cmd, now);
+            }
+            state
+        };
+        let shortfall = run(0).deficits[0].shortfall;
+        assert!(shortfall > 600);
+        // 2 ETH are worth 600, all taken; the deficit is what is left
+        let state = run(2);
+        assert_eq!(state.deficits[0].shortfall, shortfall - 600);
+        assert_eq!(state.asset_balance("alice", Some("ETH")), 0);
+        assert_eq!(state.ledger.asset_balance("alice", Some("ETH")), 0);
+        // with 10 ETH only what covers the shortfall is sold and no deficit opens
+        let state = run(10);
+        let sold = (shortfall + 299) / 300;
+        assert!(state.deficits.is_empty() && !state.is_blocked("alice"));
+        assert_eq!(state.asset_balance("alice", Some("ETH")), 10 - sold);
+        assert_eq!(state.accounts["alice"].collateral, sold * 300 - shortfall);
+        assert_eq!(state.ledger.entries("alice").last().map(|e| (e.kind, e.reference.clone())), Some((LedgerKind::Conversion, Some("conversion:ETH".into()))));
+        assert_eq!(state.ledger.balance("alice"), state.accounts["alice"].collateral);
+    }
+
+    #[test]
+    fn test_fee_tiers_and_rebates() {
+        let mut state = EngineState::new(EngineConfig::default());
+        let tiers = vec![crate::FeeTier { min_volume: 0, rates: FeeRates { maker_bps: 2, taker_bps: 5 } }, crate::FeeTier { min_volume: 10_000, rates: FeeRates { maker_bps: -1, taker_bps: 3 } }];
+        state.apply(EngineCommand::SetFeeSchedule(FeeSchedule { tiers }), 1);
+        for t in ["alice", "bob"] { state.apply(EngineCommand::Deposit { trader: t.into(), amount: 10_000, asset: None }, 1); }
+        for now in [2, 3] {
+            state.apply(order("bob", Side::Sell, 100, 100), now);
+            state.apply(order("alice", Side::Buy, 100, 100), now);
+            state.apply(EngineCommand::Tick { txs: None }, now);
+        }
+        // the first trade takes both to 10_000 of volume, so the second is charged at tier 1 and bob is paid a rebate
+        let fees: Vec<(i128, i128, i64, i64)> = state.trades.iter().map(|t| (t.maker_fee, t.taker_fee, t.maker_fee_bps, t.taker_fee_bps)).collect();
+        assert_eq!(fees, vec![(2, 5, 2, 5), (-1, 3, -1, 3)]);
+        assert_eq!(state.fee_rates("bob"), (20_000, Some(1), FeeRates { maker_bps: -1, taker_bps: 3 }));
+        assert_eq!(state.ledger.entries("bob").last().map(|e| (e.kind, e.amount)), Some((LedgerKind::Fee, 1)));
+        assert_eq!(state.treasury.balance, 2 + 5 - 1 + 3);
+        // a rebate never takes the treasury below zero
+        let mut state = EngineState::new(EngineConfig::default());
+        state.apply(EngineCommand::SetFeeOverride { trader: "alice".into(), rates: Some(FeeRates { maker_bps: 0, taker_bps: 0 }) }, 1);
+        state.apply(EngineCommand::SetFeeOverride { trader: "bob".into(), rates: Some(FeeRates { maker_bps: -5, taker_bps: 5 }) }, 1);
+        for t in ["alice", "bob"] { state.apply(EngineCommand::Deposit { trader: t.into(), amount: 10_000, asset: None }, 1); }
+        state.apply(order("bob", Side::Sell, 100, 100), 2);
+        state.apply(order("alice", Side::Buy, 100, 100), 2);
+        state.apply(EngineCommand::Tick { txs: None }, 2);
+        assert_eq!(state.trades.back().map(|t| (t.maker_fee, t.taker_fee)), Some((0, 0)));
+        assert_eq!(state.treasury.balance, 0);
+        assert_eq!(state.fee_rates("bob").1, None);
+        let ev = state.apply(EngineCommand::SetFeeOverride { trader: "bob".into(), rates: Some(FeeRates { maker_bps: -6, taker_bps: 5 }) }, 3);
+        assert!(matches!(ev.as_slice(), [EngineEvent::Refused { refusal: Refusal::InvalidFees { .. } }]));
+        state.apply(EngineCommand::SetFeeOverride { trader: "bob".into(), rates: None }, 3);
+        assert_eq!(state.fee_rates("bob").1, Some(0));
+        // a fee never takes more than the payer's collateral is worth
+        let mut state = EngineState::new(EngineConfig::default());
+        state.apply(EngineCommand::SetFeeOverride { trader: "alice".into(), rates: Some(FeeRates { maker_bps: 0, taker_bps: 10_000 }) }, 1);
+        for t in ["alice", "bob"] { state.apply(EngineCommand::Deposit { trader: t.into(), amount: 1_000, asset: None }, 1); }
+        state.apply(order("bob", Side::Sell, 100, 100), 2);
+        state.apply(order("alice", Side::Buy, 100, 100), 2);
+        let m = state.peek_match().unwrap();
+        assert_eq!(state.match_fees(&m), MatchFees { maker_fee: 2, taker_fee: 1_000, maker_bps: 2, taker_bps: 10_000 });
+        state.apply(EngineCommand::Tick { txs: None }, 2);
+        assert_eq!(state.trades.back().map(|t| t.taker_fee), Some(1_000));
+        assert_eq!(state.accounts["alice"].collateral, 0);
+        // other collateral pays too, leaving the settlement balance negative
+        let mut state = EngineState::new(EngineConfig::default());
+        let eth = CollateralAsset { haircut_bps: 0, source: PriceSource::Feed, price: 0, price_ts: 0 };
+        state.apply(EngineCommand::SetCollateralAsset { asset: "ETH".into(), config: eth }, 1);
+        state.apply(EngineCommand::SetCollateralPrice { asset: "ETH".into(), price: 1_000 }, 1);
+        state.apply(EngineCommand::SetFeeOverride { trader: "alice".into(), rates: Some(FeeRates { maker_bps: 0, taker_bps: 100 }) }, 1);
+        state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 1, asset: Some("ETH".into()) }, 1);
+        state.apply(EngineCommand::Deposit { trader: "bob".into(), amount: 1_000, asset: None }, 1);
+        state.apply(order("bob", Side::Sell, 100, 5), 2);
+        state.apply(order("alice", Side::Buy, 100, 5), 2);
+        state.apply(EngineCommand::Tick { txs: None }, 2);
+        assert_eq!(state.trades.back().map(|t| t.taker_fee), Some(5));
+        assert_eq!((state.accounts["alice"].collateral, state.collateral_value("alice")), (-5, 995));
+    }
+
+    fn onchain(cmd: EngineCommand, id: u64) -> EngineCommand {
+        match cmd {
+            EngineCommand::PlaceOrder(o) => EngineCommand::PlaceOrder(NewOrder { onchain: Some((id, format!("0x{}", id))), ..o }),
+            other => other,
+        }
+    }
+
+    #[test]
+    fn test_onchain_orders_get_local_ids() {
+        let mut state = EngineState::new(EngineConfig::default());
+        // rejected before alice has an account, under local id 1
+        state.apply(order("alice", Side::Buy, 100, 10), 1);
+        for t in ["alice", "bob"] { state.apply(EngineCommand::Deposit { trader: t.into(), amount: 10_000, asset: None }, 1); }
+        // the contract numbers its own orders from 1 as well
+        let ev = state.apply(onchain(order("bob", Side::Sell, 100, 10), 1), 2);
+        assert!(matches!(ev.first(), Some(EngineEvent::OrderAccepted { id: 2, .. })));
+        state.apply(order("alice", Side::Sell, 100, 10), 2);
+        state.apply(onchain(order("alice", Side::Buy, 100, 10), 2), 2);
+        assert_eq!(state.orders[&1].status, crate::OrderStatus::Rejected);
+        assert_eq!((state.orders[&2].onchain_id, state.orders[&3].onchain_id, state.orders[&4].onchain_id), (Some(1), None, Some(2)));
+        assert_eq!(state.book.sells.iter().map(|(id, o)| (*id, o.onchain_id)).collect::<Vec<_>>(), vec![(2, Some(1)), (3, None)]);
+        let m = state.peek_match().unwrap();
+        assert_eq!((m.buy_id, m.sell_id, m.buy_onchain_id, m.sell_onchain_id), (4, 2, Some(2), Some(1)));
+    }
+
+    #[test]
+    fn test_resting_order_is_maker() {
+        let mut state = EngineState::new(EngineConfig::default());
+        for t in ["alice", "bob"] { state.apply(EngineCommand::Deposit { trader: t.into(), amount: 10_000, asset: None }, 1); }
+        // on-chain ids need not follow arrival order; the sell arrives first under the higher one
+        state.apply(onchain(order("bob", Side::Sell, 100, 100), 9), 2);
+        state.apply(onchain(order("alice", Side::Buy, 100, 100), 3), 2);
+        let m = state.peek_match().unwrap();
+        assert_eq!((m.buy_id, m.sell_id, m.maker_side), (2, 1, Side::Sell));
+        assert_eq!((m.buy_onchain_id, m.sell_onchain_id), (Some(3), Some(9)));
+        state.apply(EngineCommand::Tick { txs: None }, 2);
+        let t = state.trades.back().unwrap();
+        assert_eq!((t.maker_side, t.maker_fee, t.taker_fee), (Side::Sell, 2, 5));
+        assert_eq!((state.orders[&1].fees_paid, state.orders[&2].fees_paid), (2, 5));
+        // and the other way round: a resting bid is the maker
+        state.apply(order("alice", Side::Buy, 100, 100), 3);
+        state.apply(order("bob", Side::Sell, 100, 100), 3);
+        state.apply(EngineCommand::Tick { txs: None }, 3);
+        let t = state.trades.back().unwrap();
+        assert_eq!(t.maker_side, Side::Buy);
+        assert_eq!(t.fills_for("alice")[0].liquidity, crate::Liquidity::Maker);
+        assert_eq!(state.ledger.entries("alice").last().map(|e| e.amount), Some(-2));
+    }
+
+    #[test]
+    fn test_book_changes_report_iceberg_refills() {
+        let mut state = EngineState::new(EngineConfig::default());
+        for t in ["alice", "bob"] { state.apply(EngineCommand::Deposit { trader: t.into(), amount: 10_000, asset: None }, 1); }
+        let ice = match order("alice", Side::Sell, 100, 250) {
+            EngineCommand::PlaceOrder(o) => EngineCommand::PlaceOrder(NewOrder { display_qty: Some(100), ..o }),
+            other => other,
+        };
+        state.apply(ice, 2);
+        state.apply(order("bob", Side::Buy, 100, 100), 2);
+        let ev = state.apply(EngineCommand::Tick { txs: None }, 3);
+        assert!(ev.contains(&EngineEvent::OrderFilled { id: 1, trader: "alice".into(), price: 100, qty: 100, fee: 2, done: false }));
+        let book = |ev: &[EngineEvent]| -> Vec<(BookAction, u64, i128)> {
+            ev.iter().filter_map(|e| match e { EngineEvent::BookChanged { action, id, qty, .. } => Some((*action, *id, *qty)), _ => None }).collect()
+        };
+        assert_eq!(book(&ev), vec![(BookAction::Remove, 2, 0), (BookAction::Refill, 1, 100)]);
+        // a partial fill keeps the slice in place; the last slice is what is left of the reserve
+        state.apply(order("bob", Side::Buy, 100, 40), 4);
+        let ev = state.apply(EngineCommand::Tick { txs: None }, 5);
+        assert_eq!(book(&ev), vec![(BookAction::Remove, 3, 0), (BookAction::Update, 1, 60)]);
+        state.apply(order("bob", Side::Buy, 100, 60), 6);
+        let ev = state.apply(EngineCommand::Tick { txs: None }, 7);
+        assert_eq!(book(&ev), vec![(BookAction::Remove, 4, 0), (BookAction::Refill, 1, 50)]);
+    }
+
+    #[test]
+    fn test_sub_accounts_and_transfers() {
+        let mut state = EngineState::new(EngineConfig::default());
+        state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 1_000, asset: None }, 1);
+        state.apply(EngineCommand::Deposit { trader: "bob".into(), amount: 1_000, asset: None }, 1);
+        let create = |master: &str, sub: &str| EngineCommand::CreateSubAccount { master: master.into(), sub: sub.into() };
+        let transfer = |from: &str, to: &str, amount| EngineCommand::Transfer { from: from.into(), to: to.into(), amount };
+        let refused = |ev: Vec<EngineEvent>| match ev.as_slice() { [EngineEvent::Refused { refusal }] => Some(refusal.clone()), _ => None };
+        assert_eq!(state.apply(create("alice", "alice/arb"), 2), vec![EngineEvent::SubAccountCreated { master: "alice".into(), sub: "alice/arb".into() }]);
+        state.apply(create("alice", "alice/mm"), 2);
+        assert_eq!(refused(state.apply(create("alice", "bob"), 2)), Some(Refusal::AccountExists));
+        assert_eq!(refused(state.apply(create("carol", "carol/x"), 2)), Some(Refusal::AccountNotFound));
+        assert!(matches!(refused(state.apply(create("alice/arb", "alice/arb/x"), 2)), Some(Refusal::InvalidSubAccount { .. })));
+        // master -> sub, sub -> sub; never across masters or beyond free collateral
+        state.apply(transfer("alice", "alice/arb", 600), 3);
+        state.apply(transfer("alice/arb", "alice/mm", 100), 3);
+        assert!(matches!(refused(state.apply(transfer("alice/arb", "bob", 1), 3)), Some(Refusal::InvalidTransfer { .. })));
+        assert_eq!(refused(state.apply(transfer("alice", "alice/mm", 401), 3)), Some(Refusal::InsufficientCollateral));
+        let balances: Vec<i128> = ["alice", "alice/arb", "alice/mm"].iter().map(|t| state.accounts[*t].collateral).collect();
+        assert_eq!(balances, vec![400, 500, 100]);
+        assert_eq!(state.ledger.entries("alice/mm")[0].reference.as_deref(), Some("transfer:alice/arb"));
+        assert_eq!(state.master_of("alice/mm"), "alice");
+        assert_eq!(state.subs_of("alice").collect::<Vec<_>>(), vec!["alice/arb", "alice/mm"]);
+        // margin locked in a sub stays there
+        state.apply(order("alice/arb", Side::Buy, 100, 40), 4);
+        assert_eq!(state.free_collateral("alice/arb"), 100);
+        assert_eq!(refused(state.apply(transfer("alice/arb", "alice", 101), 4)), Some(Refusal::InsufficientCollateral));
+        // free collateral backed only by another asset cannot move
+        let eth = CollateralAsset { haircut_bps: 0, source: PriceSource::Feed, price: 0, price_ts: 0 };
+        state.apply(EngineCommand::SetCollateralAsset { asset: "ETH".into(), config: eth }, 5);
+        state.apply(EngineCommand::SetCollateralPrice { asset: "ETH".into(), price: 1_000 }, 5);
+        state.apply(EngineCommand::Deposit { trader: "alice/mm".into(), amount: 1, asset: Some("ETH".into()) }, 5);
+        assert_eq!(state.free_collateral("alice/mm"), 1_100);
+        assert_eq!(refused(state.apply(transfer("alice/mm", "alice", 101), 5)), Some(Refusal::InsufficientCollateral));
+        state.apply(transfer("alice/mm", "alice", 100), 5);
+        assert_eq!(state.accounts["alice/mm"].collateral, 0);
+    }
+
+    #[test]
+    fn test_multi_collateral_with_haircuts() {
+        let mut state = EngineState::new(EngineConfig::default());
+        let eth = CollateralAsset { haircut_bps: 2_000, source: PriceSource::Feed, price: 0, price_ts: 0 };
+        state.apply(EngineCommand::SetCollateralAsset { asset: "ETH".into(), config: eth }, 1);
+        let deposit = |asset: Option<&str>, amount| EngineCommand::Deposit { trader: "alice".into(), amount, asset: asset.map(Into::into) };
+        let withdraw = |asset: Option<&str>, amount| EngineCommand::Withdraw { trader: "alice".into(), amount, asset: asset.map(Into::into) };
+        assert_eq!(state.apply(deposit(Some("BTC"), 1), 1), vec![EngineEvent::Refused { refusal: Refusal::UnknownAsset }]);
+        state.apply(deposit(Some("ETH"), 2), 1);
+        assert_eq!(state.collateral_value("alice"), 0); // no price yet
+        state.apply(EngineCommand::SetCollateralPrice { asset: "ETH".into(), price: 500 }, 2);
+        assert_eq!(state.collateral_value("alice"), 800);
+        state.apply(order("alice", Side::Buy, 100, 50), 2);
+        assert_eq!(state.free_collateral("alice"), 300);
+        // 1 ETH counts for 400, more than is free; and there are no dollars to take out
+        assert_eq!(state.apply(withdraw(Some("ETH"), 1), 3), vec![EngineEvent::Refused { refusal: Refusal::InsufficientCollateral }]);
+        assert_eq!(state.apply(withdraw(None, 1), 3), vec![EngineEvent::Refused { refusal: Refusal::InsufficientCollateral }]);
+        state.apply(deposit(Some("USD"), 200), 3);
+        assert!(state.apply(withdraw(None, 200), 3).contains(&EngineEvent::Withdrawal { trader: "alice".into(), amount: 200, asset: None }));
+        state.apply(EngineCommand::SetCollateralPrice { asset: "ETH".into(), price: 1_000 }, 4);
+        state.apply(withdraw(Some("ETH"), 1), 4);
+        assert_eq!((state.asset_balance("alice", Some("ETH")), state.collateral_value("alice")), (1, 800));
+        assert_eq!(state.ledger.asset_balance("alice", Some("ETH")), 1);
+        assert_eq!(state.ledger.balance("alice"), state.accounts["alice"].collateral);
+    }
+
+    #[test]
+    fn test_replay_is_deterministic() {
+        let run = || {
+            let mut state = EngineState::new(EngineConfig::default());
+            let mut events = Vec::new();
+            for (now, cmd) in session() {
+                let (next, ev) = apply(state, cmd, now);
+                state = next;
+                events.extend(ev);
+            }
+            (serde_json::to_string(&state).unwrap(), serde_json::to_string(&events).unwrap())
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn test_round_trip_releases_margin() {
+        let mut state = EngineState::new(EngineConfig::default());
+        for t in ["alice", "bob"] { state.apply(EngineCommand::Deposit { trader: t.into(), amount: 1_000, asset: None }, 1); }
+        state.apply(order("alice", Side::Buy, 100, 50), 2);
+        state.apply(order("bob", Side::Sell, 100, 30), 2);
+        state.apply(EngineCommand::Tick { txs: None }, 2);
+        // 30 filled back the position; 20 still rest
+        assert_eq!((state.accounts["alice"].locked_margin, state.positions["alice"].margin), (500, 300));
+        state.apply(EngineCommand::CancelOrder { trader: "alice".into(), id: Some(1), client_order_id: None }, 3);
+        state.apply(order("alice", Side::Sell, 100, 30), 3);
+        state.apply(order("bob", Side::Buy, 100, 30), 3);
+        state.apply(EngineCommand::Tick { txs: None }, 3);
+        for t in ["alice", "bob"] { assert_eq!((state.accounts[t].locked_margin, state.positions[t].margin), (0, 0)); }
+        // flat again, so most of the collateral can back a new order
+        let ev = state.apply(order("alice", Side::Buy, 100, 95), 4);
+        assert!(matches!(ev.as_slice(), [EngineEvent::OrderAccepted { .. }, ..]), "{:?}", ev);
+        assert_eq!(state.accounts["alice"].locked_margin, 950);
+    }
+
+    #[test]
+    fn test_orders_expire_after_ttl() {
+        let clock = crate::ManualClock::new(1_000);
+        let mut state = EngineState::new(EngineConfig::default());
+        state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 10_000, asset: None }, clock.now());
+        state.apply(order("alice", Side::Buy, 100, 50), clock.now());
+        assert_eq!(state.book.best_buy().map(|(_, o)| (o.ts, o.expiry_ts)), Some((1_000, 1_600)));
+        assert_eq!(state.accounts["alice"].locked_margin, 500);
+        clock.advance(599);
+        assert!(state.apply(EngineCommand::Tick { txs: None }, clock.now()).iter().all(|e| !matches!(e, EngineEvent::OrderCancelled { .. })));
+        clock.advance(1);
+        let ev = state.apply(EngineCommand::Tick { txs: None }, clock.now());
+        assert!(ev.contains(&EngineEvent::OrderCancelled { id: 1, trader: "alice".into(), client_order_id: None, cancelled_qty: 50, reason: CancelReason::Expired }));
+        assert!(state.book.buys.is_empty());
+        assert_eq!(state.accounts["alice"].locked_margin, 0);
+        assert_eq!((state.orders[&1].status, state.orders[&1].closed_ts), (crate::OrderStatus::Expired, Some(1_600)));
+    }
+
+    #[test]
+    fn test_positions_and_oracle_are_stamped() {
+        let mut state = EngineState::new(EngineConfig::default());
+        for (now, cmd) in session().into_iter().take(5) { state.apply(cmd, now); }
+        assert_eq!((state.positions["bob"].opened_ts, state.positions["bob"].expiry_ts), (3, 3 + 86_400));
+        state.apply(EngineCommand::SetOracle { price: 101, source: OracleSource::Admin }, 7);
+        assert_eq!((state.oracle.ts, state.oracle_source), (7, Some(OracleSource::Admin)));
+    }
+
+    #[test]
+    fn test_client_ids_are_pruned() {
+        let mut state = EngineState::new(EngineConfig { client_id_window_secs: 10, order_retention_secs: 20, trade_retention: 100 });
+        state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 10_000, asset: None }, 1);
+        let EngineCommand::PlaceOrder(o) = order("alice", Side::Buy, 100, 1) else { unreachable!() };
+        state.apply(EngineCommand::PlaceOrder(NewOrder { client_order_id: Some("a".into()), ttl_secs: 1_000, ..o }), 1);
+        // past the window but still resting
+        state.apply(EngineCommand::Tick { txs: None }, 50);
+        assert_eq!(state.client_order("alice", "a"), Some(1));
+        state.apply(EngineCommand::CancelOrder { trader: "alice".into(), id: Some(1), client_order_id: None }, 50);
+        // closed, and kept as long as the order record is
+        state.apply(EngineCommand::Tick { txs: None }, 69);
+        assert_eq!(state.client_order("alice", "a"), Some(1));
+        state.apply(EngineCommand::Tick { txs: None }, 70);
+        assert_eq!(state.client_order("alice", "a"), None);
+        assert!(state.client_ids.is_empty() && state.orders.is_empty());
+    }
+
+    #[test]
+    fn test_refusals_change_nothing() {
+        let mut state = EngineState::new(EngineConfig::default());
+        state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 100, asset: None }, 1);
+        let before = serde_json::to_string(&state).unwrap();
+        let ev = state.apply(EngineCommand::Withdraw { trader: "alice".into(), amount: 101, asset: None }, 1);
+        assert_eq!(ev, vec![EngineEvent::Refused { refusal: Refusal::InsufficientCollateral }]);
+        let ev = state.apply(EngineCommand::CancelOrder { trader: "alice".into(), id: Some(9), client_order_id: None }, 1);
+        assert_eq!(ev, vec![EngineEvent::Refused { refusal: Refusal::OrderNotFound }]);
+        for amount in [0, -5] {
+            let ev = state.apply(EngineCommand::Deposit { trader: "alice".into(), amount, asset: None }, 1);
+            assert_eq!(ev, vec![EngineEvent::Refused { refusal: Refusal::InvalidAmount }]);
+            let ev = state.apply(EngineCommand::Withdraw { trader: "alice".into(), amount, asset: None }, 1);
+            assert_eq!(ev, vec![EngineEvent::Refused { refusal: Refusal::InvalidAmount }]);
+        }
+        assert_eq!(serde_json::to_string(&state).unwrap(), before);
+    }
+
+    #[test]
+    fn test_pre_trade_rejects() {
+        let mut state = EngineState::new(EngineConfig::default());
+        let code = |ev: Vec<EngineEvent>| match ev.as_slice() {
+            [EngineEvent::OrderRejected { reject, .. }] => Some(reject.clone()),
+            _ => None,
+        };
+        // no account, so no collateral and no margin locked on a fresh account
+        assert_eq!(code(state.apply(order("alice", Side::Buy, 100, 10), 1)), Some(RejectReason::AccountNotFound));
+        assert!(!state.accounts.contains_key("alice"));
+        state.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 250, asset: None }, 1);
+        assert_eq!(code(state.apply(order("alice", Side::Buy, 100, 30), 1)), Some(RejectReason::InsufficientMargin { required: 300, free: 250 }));
+        state.apply(EngineCommand::SetRiskLimits(RiskLimits { rate_limit: 2, rate_window_secs: 10, ..RiskLimits::default() }), 1);
+        for _ in 0..2 { assert_eq!(code(state.apply(order("alice", Side::Buy, 100, 1), 2)), None); }
+        assert_eq!(code(state.apply(order("alice", Side::Buy, 100, 1), 11)), Some(RejectReason::RateLimited { max: 2, window_secs: 10 }));
+        assert_eq!(code(state.apply(order("alice", Side::Buy, 100, 1), 12)), None);
+        assert_eq!(state.accounts["alice"].locked_margin, 30);
+        let ev = state.apply(EngineCommand::SetRiskLimits(RiskLimits { rate_limit: 1, rate_window_secs: 0, ..RiskLimits::default() }), 13);
+        assert!(matches!(ev.as_slice(), [EngineEvent::Refused { refusal: Refusal::InvalidRiskLimits { .. } }]));
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use crate::{Order, Side};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus { Open, PartiallyFilled, Filled, Cancelled, Rejected, Expired }
+
+impl OrderStatus {
+    pub fn is_closed(self) -> bool {
+        matches!(self, OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Rejected | OrderStatus::Expired)
+    }
+}
+
+/// Lifecycle summary of one order, kept after it leaves the book so clients
+/// can find out what happened to it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OrderRecord {
+    pub id: u64,
+    pub trader: String,
+    pub client_order_id: Option<String>,
+    pub side: Side,
+    pub price: i128,
+    pub qty: i128, // as submitted, including any iceberg reserve
+    pub leverage: u32,
+    pub status: OrderStatus,
+    pub filled_qty: i128,
+    pub avg_fill_price: Option<i128>,
+    pub fees_paid: i128,
+    pub reject_reason: Option<String>,
+    pub created_ts: u64,
+    pub closed_ts: Option<u64>,
+    pub filled_notional: i128,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub onchain_id: Option<u64>,
+}
+
+impl OrderRecord {
+    pub fn new(id: u64, order: &Order, now: u64) -> Self {
+        Self {
+            id,
+            trader: order.trader.clone(),
+            client_order_id: order.client_order_id.clone(),
+            side: order.side,
+            price: order.price,
+            qty: order.remaining_qty(),
+            leverage: order.leverage,
+            status: OrderStatus::Open,
+            filled_qty: 0,
+            avg_fill_price: None,
+            fees_paid: 0,
+            reject_reason: None,
+            created_ts: now,
+            closed_ts: None,
+            filled_notional: 0,
+            onchain_id: order.onchain_id,
+        }
+    }
+
+    pub fn rejected(id: u64, order: &Order, reason: impl Into<String>, now: u64) -> Self {
+        let mut r = Self::new(id, order, now);
+        r.status = OrderStatus::Rejected;
+        r.reject_reason = Some(reason.into());
+        r.closed_ts = Some(now);
+        r
+    }
+
+    /// Record a fill; `done` is true once nothing of the order rests in the book.
+    pub fn record_fill(&mut self, price: i128, qty: i128, fee: i128, done: bool, now: u64) {
+        self.filled_qty += qty;
+        self.filled_notional += price * qty;
+        self.fees_paid += fee;
+        if self.filled_qty != 0 { self.avg_fill_price = Some(self.filled_notional / self.filled_qty); }
+        if done { self.close(OrderStatus::Filled, now); } else { self.status = OrderStatus::PartiallyFilled; }
+    }
+
+    /// Mark as cancelled; an order that had fills keeps them.
+    pub fn cancel(&mut self, now: u64) { self.close(OrderStatus::Cancelled, now); }
+
+    /// Mark as expired: its TTL ran out while it was resting.
+    pub fn expire(&mut self, now: u64) { self.close(OrderStatus::Expired, now); }
+
+    fn close(&mut self, status: OrderStatus, now: u64) {
+        self.status = status;
+        self.closed_ts = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StpMode;
+
+    fn order() -> Order {
+        Order { trader: "a".into(), side: Side::Buy, price: 100, qty: 500, leverage: 10, ts: 0, expiry_ts: 86_400, is_limit: true, iceberg: None, stp: StpMode::default(), client_order_id: None, seq: 0, onchain_id: None }
+    }
+
+    #[test]
+    fn test_fills_track_average_and_status() {
+        let mut r = OrderRecord::new(1, &order().with_display(100), 10);
+        assert_eq!(r.qty, 500);
+        r.record_fill(100, 100, 5, false, 11);
+        assert_eq!(r.status, OrderStatus::PartiallyFilled);
+        r.record_fill(103, 200, 10, false, 12);
+        assert_eq!(r.avg_fill_price, Some(102));
+        assert_eq!(r.fees_paid, 15);
+        r.record_fill(102, 200, 10, true, 13);
+        assert_eq!((r.status, r.filled_qty, r.closed_ts), (OrderStatus::Filled, 500, Some(13)));
+    }
+
+    #[test]
+    fn test_rejected_is_closed() {
+        let r = OrderRecord::rejected(2, &order(), "display_qty must be in 1..=qty", 7);
+        assert!(r.status.is_closed());
+        assert_eq!(r.reject_reason.as_deref(), Some("display_qty must be in 1..=qty"));
+        assert_eq!(r.closed_ts, Some(7));
+    }
+}
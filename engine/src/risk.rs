@@ -1,10 +1,109 @@
-use crate::{Account, OraclePrice, Position};
+use serde::{Deserialize, Serialize};
+use crate::{Account, NewOrder, OraclePrice, Position};
 use thiserror::Error;
 
-#[derive(Debug, Error)]
-pub enum RiskError {
-    #[error("insufficient collateral: needed {needed}, have {have}")]
-    InsufficientCollateral { needed: i128, have: i128 },
+/// One pre-trade check. They run in the order `RiskLimits.checks` lists them.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskCheck { AccountExists, FreeCollateral, Leverage, PriceBand, MaxSize, OpenOrders, RateLimit }
+
+/// Why an order was rejected before reaching the book. Serialized with a
+/// `code` tag and the numbers that failed the check.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Error)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum RejectReason {
+    #[error("display_qty must be in 1..=qty")]
+    InvalidDisplayQty,
+    #[error("account blocked by an unresolved deficit")]
+    AccountBlocked,
+    #[error("account not found")]
+    AccountNotFound,
+    #[error("insufficient collateral: margin {required}, free {free}")]
+    InsufficientMargin { required: i128, free: i128 },
+    #[error("leverage {leverage} above the max {max}")]
+    LeverageTooHigh { leverage: u32, max: u32 },
+    #[error("price {price} outside the band {low}..={high}")]
+    PriceOutsideBand { price: i128, low: i128, high: i128 },
+    #[error("qty {qty} above the max {max}")]
+    OrderTooLarge { qty: i128, max: i128 },
+    #[error("{open} open orders, the max is {max}")]
+    TooManyOpenOrders { open: usize, max: usize },
+    #[error("more than {max} orders in {window_secs}s")]
+    RateLimited { max: usize, window_secs: u64 },
+}
+
+/// Limits for the pre-trade checks. A limit of 0 turns its check off; a
+/// check left out of `checks` does not run at all.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RiskLimits {
+    pub checks: Vec<RiskCheck>,
+    pub max_leverage: u32,
+    pub price_band_bps: u64, // how far from the mark a price may be
+    pub max_order_qty: i128,
+    pub max_open_orders: usize, // resting orders per trader
+    pub rate_limit: usize, // accepted orders per trader per window
+    pub rate_window_secs: u64,
+}
+
+impl Default for RiskLimits {
+    fn default() -> Self {
+        use RiskCheck::*;
+        Self {
+            checks: vec![AccountExists, FreeCollateral, Leverage, PriceBand, MaxSize, OpenOrders, RateLimit],
+            max_leverage: 100,
+            price_band_bps: 5_000,
+            max_order_qty: 1_000_000,
+            max_open_orders: 200,
+            rate_limit: 100,
+            rate_window_secs: 1,
+        }
+    }
+}
+
+/// What the checks need to know about the trader and the market.
+#[derive(Debug, Clone, Copy)]
+pub struct PreTrade {
+    pub account_exists: bool,
+    pub free_collateral: i128,
+    pub mark: i128,
+    pub open_orders: usize,
+    pub recent_orders: usize, // accepted inside the rate window
+}
+
+impl RiskLimits {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.checks.iter().enumerate().any(|(i, c)| self.checks[..i].contains(c)) { return Err("a check is listed twice"); }
+        if self.rate_limit > 0 && self.rate_window_secs == 0 { return Err("rate_window_secs must be positive"); }
+        if self.max_order_qty < 0 { return Err("max_order_qty must not be negative"); }
+        Ok(())
+    }
+
+    /// Run the checks in order; the first one that fails rejects the order.
+    pub fn check(&self, o: &NewOrder, ctx: &PreTrade) -> Result<(), RejectReason> {
+        self.checks.iter().try_for_each(|c| self.run(*c, o, ctx))
+    }
+
+    fn run(&self, check: RiskCheck, o: &NewOrder, ctx: &PreTrade) -> Result<(), RejectReason> {
+        match check {
+            RiskCheck::AccountExists if !ctx.account_exists => Err(RejectReason::AccountNotFound),
+            RiskCheck::FreeCollateral => {
+                let required = required_margin(o.qty, o.price, o.leverage);
+                if ctx.free_collateral < required { return Err(RejectReason::InsufficientMargin { required, free: ctx.free_collateral }); }
+                Ok(())
+            }
+            RiskCheck::Leverage if self.max_leverage > 0 && o.leverage > self.max_leverage => Err(RejectReason::LeverageTooHigh { leverage: o.leverage, max: self.max_leverage }),
+            RiskCheck::PriceBand if self.price_band_bps > 0 => {
+                let width = ctx.mark.abs() * self.price_band_bps as i128 / 10_000;
+                let (low, high) = (ctx.mark - width, ctx.mark + width);
+                if o.price < low || o.price > high { return Err(RejectReason::PriceOutsideBand { price: o.price, low, high }); }
+                Ok(())
+            }
+            RiskCheck::MaxSize if self.max_order_qty > 0 && o.qty > self.max_order_qty => Err(RejectReason::OrderTooLarge { qty: o.qty, max: self.max_order_qty }),
+            RiskCheck::OpenOrders if self.max_open_orders > 0 && ctx.open_orders >= self.max_open_orders => Err(RejectReason::TooManyOpenOrders { open: ctx.open_orders, max: self.max_open_orders }),
+            RiskCheck::RateLimit if self.rate_limit > 0 && ctx.recent_orders >= self.rate_limit => Err(RejectReason::RateLimited { max: self.rate_limit, window_secs: self.rate_window_secs }),
+            _ => Ok(()),
+        }
+    }
 }
 
 pub fn required_margin(qty: i128, price: i128, leverage: u32) -> i128 {
@@ -43,6 +142,23 @@ mod tests {
         assert_eq!(pnl_unrealized(&p,&m), 10_000);
     }
 
+    #[test]
+    fn test_pre_trade_checks_in_order() {
+        let o = NewOrder { trader: "t".into(), side: crate::Side::Buy, price: 100, qty: 10, leverage: 10, ttl_secs: 60, is_limit: true, display_qty: None, stp_mode: None, client_order_id: None, onchain: None };
+        let ctx = PreTrade { account_exists: true, free_collateral: 100, mark: 100, open_orders: 0, recent_orders: 0 };
+        let limits = RiskLimits::default();
+        assert_eq!(limits.check(&o, &ctx), Ok(()));
+        assert_eq!(limits.check(&o, &PreTrade { free_collateral: 99, ..ctx }), Err(RejectReason::InsufficientMargin { required: 100, free: 99 }));
+        // the account check comes first
+        assert_eq!(limits.check(&o, &PreTrade { account_exists: false, free_collateral: 0, ..ctx }), Err(RejectReason::AccountNotFound));
+        assert_eq!(limits.check(&NewOrder { price: 151, ..o.clone() }, &PreTrade { free_collateral: 1_000, ..ctx }), Err(RejectReason::PriceOutsideBand { price: 151, low: 50, high: 150 }));
+        assert_eq!(limits.check(&NewOrder { leverage: 101, ..o.clone() }, &ctx), Err(RejectReason::LeverageTooHigh { leverage: 101, max: 100 }));
+        assert_eq!(limits.check(&o, &PreTrade { recent_orders: 100, ..ctx }), Err(RejectReason::RateLimited { max: 100, window_secs: 1 }));
+        let only_size = RiskLimits { checks: vec![RiskCheck::MaxSize], max_order_qty: 5, ..limits.clone() };
+        assert_eq!(only_size.check(&o, &PreTrade { account_exists: false, ..ctx }), Err(RejectReason::OrderTooLarge { qty: 10, max: 5 }));
+        assert!(RiskLimits { checks: vec![RiskCheck::MaxSize, RiskCheck::MaxSize], ..limits }.validate().is_err());
+    }
+
     #[test]
     fn test_margin_health() {
         let acc = Account{ collateral: 20_000, locked_margin:10_000};
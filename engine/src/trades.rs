@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use crate::Side;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Liquidity { Maker, Taker }
+
+/// One executed match between a resting buy and a resting sell.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TradeExecution {
+    pub id: u64,
+    pub ts: u64,
+    pub price: i128,
+    pub qty: i128,
+    pub buy_id: u64, // order ids
+    pub sell_id: u64,
+    pub buy_trader: String,
+    pub sell_trader: String,
+    pub maker_side: Side,
+    pub maker_fee: i128, // negative for a rebate
+    pub taker_fee: i128,
+    #[serde(default)]
+    pub maker_fee_bps: i64, // rates the fees were charged at
+    #[serde(default)]
+    pub taker_fee_bps: i64,
+}
+
+/// A trade seen from one trader's side.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Fill {
+    pub trade_id: u64,
+    pub ts: u64,
+    pub order_id: u64,
+    pub side: Side,
+    pub price: i128,
+    pub qty: i128,
+    pub fee: i128,
+    #[serde(default)]
+    pub fee_bps: i64,
+    pub liquidity: Liquidity,
+}
+
+impl TradeExecution {
+    pub fn involves(&self, trader: &str) -> bool {
+        self.buy_trader == trader || self.sell_trader == trader
+    }
+
+    /// The trader's fills in this trade: one per side they were on, so two
+    /// for a self-trade.
+    pub fn fills_for(&self, trader: &str) -> Vec<Fill> {
+        let mut out = Vec::new();
+        for (side, who, order_id) in [(Side::Buy, &self.buy_trader, self.buy_id), (Side::Sell, &self.sell_trader, self.sell_id)] {
+            if who != trader { continue; }
+            let (liquidity, fee, fee_bps) = if side == self.maker_side { (Liquidity::Maker, self.maker_fee, self.maker_fee_bps) } else { (Liquidity::Taker, self.taker_fee, self.taker_fee_bps) };
+            out.push(Fill { trade_id: self.id, ts: self.ts, order_id, side, price: self.price, qty: self.qty, fee, fee_bps, liquidity });
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fills_for_each_side() {
+        let t = TradeExecution { id: 7, ts: 100, price: 100, qty: 5, buy_id: 1, sell_id: 2, buy_trader: "a".into(), sell_trader: "b".into(), maker_side: Side::Sell, maker_fee: 1, taker_fee: 3, maker_fee_bps: 2, taker_fee_bps: 5 };
+        let a = t.fills_for("a");
+        assert_eq!(a.len(), 1);
+        assert_eq!((a[0].order_id, a[0].side, a[0].liquidity, a[0].fee), (1, Side::Buy, Liquidity::Taker, 3));
+        let b = t.fills_for("b");
+        assert_eq!((b[0].order_id, b[0].liquidity, b[0].fee, b[0].fee_bps), (2, Liquidity::Maker, 1, 2));
+        assert!(t.fills_for("c").is_empty() && !t.involves("c"));
+    }
+}
@@ -13,6 +13,77 @@ pub struct Order {
     pub ts: u64,
     pub expiry_ts: u64,
     pub is_limit: bool,
+    #[serde(default)]
+    pub iceberg: Option<Iceberg>,
+    #[serde(default)]
+    pub stp: StpMode,
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+    #[serde(default)]
+    pub seq: u64, // arrival order, whatever id the order is booked under
+    #[serde(default)]
+    pub onchain_id: Option<u64>, // the contract's id, if the order was placed on-chain first
+}
+
+/// What to do when two orders from the same trader would match.
+/// The mode of the newer (aggressing) order decides.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StpMode {
+    #[default]
+    CancelNewest,
+    CancelOldest,
+    CancelBoth,
+    /// Reduce both by the smaller remaining qty, cancelling whichever hits zero.
+    DecrementAndCancel,
+}
+
+/// Iceberg reserve: only `qty` of the order is shown in the book, the rest
+/// waits in `hidden_qty` and is released `display_qty` at a time.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Iceberg {
+    pub display_qty: i128,
+    pub hidden_qty: i128,
+}
+
+impl Order {
+    /// Turn a plain order into an iceberg showing at most `display_qty`.
+    /// A display size that covers the whole order leaves it unchanged.
+    pub fn with_display(mut self, display_qty: i128) -> Self {
+        if display_qty > 0 && display_qty < self.qty {
+            self.iceberg = Some(Iceberg { display_qty, hidden_qty: self.qty - display_qty });
+            self.qty = display_qty;
+        }
+        self
+    }
+
+    /// Visible plus hidden quantity still working.
+    pub fn remaining_qty(&self) -> i128 {
+        self.qty + self.iceberg.map(|i| i.hidden_qty).unwrap_or(0)
+    }
+
+    /// Take `qty` off the order, hidden reserve first so the visible slice
+    /// keeps its size as long as possible.
+    pub fn reduce(&mut self, qty: i128) {
+        let mut left = qty;
+        if let Some(ice) = self.iceberg.as_mut() {
+            let from_hidden = left.min(ice.hidden_qty);
+            ice.hidden_qty -= from_hidden;
+            left -= from_hidden;
+        }
+        self.qty -= left.min(self.qty);
+    }
+
+    /// Refill the visible slice from the hidden reserve. Returns false when
+    /// there is nothing left to show.
+    pub fn replenish(&mut self) -> bool {
+        let Some(ice) = self.iceberg.as_mut() else { return false; };
+        let next = ice.display_qty.min(ice.hidden_qty);
+        if next <= 0 { return false; }
+        ice.hidden_qty -= next;
+        self.qty = next;
+        true
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -38,10 +109,3 @@ pub struct OraclePrice {
     pub conf: u64,
     pub ts: u64,
 }
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct TradeExecution {
-    pub price: i128,
-    pub qty: i128,
-    pub fee: i128,
-}
@@ -0,0 +1,329 @@
+// Server-side execution algos: a parent order is sliced into child limit
+// orders over time. Children go through the normal PlaceOrder command, so
+// they lock margin and show up in order history like any other order.
+// Parents live in memory only: they are not in the engine state, so the
+// write-ahead log and snapshots do not carry them and a restart drops every
+// running parent. Children already in the book stay there as plain orders.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::error::{check, ApiError};
+use crate::sequencer::Sequencer;
+use crate::PlaceOrderReq;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum AlgoStrategy {
+    /// Equal slices every `slice_secs` (default: a tenth of the duration).
+    Twap { #[serde(default)] slice_secs: Option<u64> },
+    /// Each tick, send `participation_bps` of the volume traded since the last tick.
+    Pov { participation_bps: u32 },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlgoStatus { Running, Completed, Cancelled, Expired, Failed }
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlgoKind { Twap, Pov }
+
+// flat so unknown fields are refused; `validate` checks the strategy's own fields
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlgoReq {
+    pub trader: String,
+    #[serde(with = "crate::exchange::side_name")]
+    pub side: engine::Side,
+    pub price: i128, // limit price for every child
+    pub qty: i128,
+    pub leverage: u32,
+    pub duration_secs: u64,
+    #[serde(default = "default_child_ttl")]
+    pub child_ttl_secs: u64,
+    pub strategy: AlgoKind,
+    #[serde(default)]
+    pub slice_secs: Option<u64>, // twap only
+    #[serde(default)]
+    pub participation_bps: Option<u32>, // pov only
+}
+
+fn default_child_ttl() -> u64 { 86_400 }
+
+impl AlgoReq {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        check(!self.trader.is_empty(), "trader", "must not be empty")?;
+        check(self.price > 0, "price", "must be positive")?;
+        check(self.qty > 0, "qty", "must be positive")?;
+        check(self.leverage > 0, "leverage", "must be at least 1")?;
+        check(self.duration_secs > 0, "duration_secs", "must be positive")?;
+        check(self.child_ttl_secs > 0, "child_ttl_secs", "must be positive")?;
+        match self.strategy {
+            AlgoKind::Twap => {
+                check(self.participation_bps.is_none(), "participation_bps", "only for pov")?;
+                check(self.slice_secs.map(|s| s > 0).unwrap_or(true), "slice_secs", "must be positive")
+            }
+            AlgoKind::Pov => {
+                check(self.slice_secs.is_none(), "slice_secs", "only for twap")?;
+                check(self.participation_bps.map(|b| (1..=10_000).contains(&b)).unwrap_or(false), "participation_bps", "must be in 1..=10000")
+            }
+        }
+    }
+
+    pub fn algo_strategy(&self) -> AlgoStrategy {
+        match self.strategy {
+            AlgoKind::Twap => AlgoStrategy::Twap { slice_secs: self.slice_secs },
+            AlgoKind::Pov => AlgoStrategy::Pov { participation_bps: self.participation_bps.unwrap_or(0) },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlgoParent {
+    pub id: u64,
+    pub trader: String,
+    #[serde(with = "crate::exchange::side_name")]
+    pub side: engine::Side,
+    pub price: i128,
+    pub qty: i128,
+    pub leverage: u32,
+    pub duration_secs: u64,
+    pub child_ttl_secs: u64,
+    #[serde(flatten)]
+    pub strategy: AlgoStrategy,
+    pub status: AlgoStatus,
+    pub sent_qty: i128,
+    pub child_ids: Vec<u64>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    started: u64, // unix secs, from the sequencer's clock
+    // traded volume seen at the previous tick (POV)
+    #[serde(skip)]
+    last_volume: Option<i128>,
+}
+
+/// What the driver task should do next for a parent. The `u64` is the
+/// clock time to ask again at.
+pub enum AlgoStep {
+    Stop,
+    Wait(u64),
+    Place(PlaceOrderReq, u64),
+}
+
+#[derive(Default)]
+pub struct AlgoBook {
+    last: u64,
+    pub parents: BTreeMap<u64, AlgoParent>,
+}
+
+impl AlgoBook {
+    pub fn insert(&mut self, req: AlgoReq, now: u64) -> u64 {
+        self.last += 1;
+        let (id, strategy) = (self.last, req.algo_strategy());
+        self.parents.insert(id, AlgoParent {
+            id,
+            trader: req.trader,
+            side: req.side,
+            price: req.price,
+            qty: req.qty,
+            leverage: req.leverage,
+            duration_secs: req.duration_secs,
+            child_ttl_secs: req.child_ttl_secs,
+            strategy,
+            status: AlgoStatus::Running,
+            sent_qty: 0,
+            child_ids: Vec::new(),
+            error: None,
+            started: now,
+            last_volume: None,
+        });
+        id
+    }
+
+    /// Decide the next child for parent `id` at `now` given the cumulative
+    /// traded volume. `children` gives the qty filled across a parent's
+    /// children and whether any of them is still resting.
+    pub fn next_step(&mut self, id: u64, now: u64, traded_volume: i128, children: impl Fn(&AlgoParent) -> (i128, bool)) -> AlgoStep {
+        let Some(p) = self.parents.get_mut(&id) else { return AlgoStep::Stop; };
+        if p.status != AlgoStatus::Running { return AlgoStep::Stop; }
+        let (filled, resting) = children(p);
+        if filled >= p.qty {
+            p.status = AlgoStatus::Completed;
+            return AlgoStep::Stop;
+        }
+        // everything is sent: wait for the children to fill or leave the book
+        if p.sent_qty >= p.qty {
+            if resting { return AlgoStep::Wait(now + p.interval_secs()); }
+            p.status = AlgoStatus::Expired;
+            return AlgoStep::Stop;
+        }
+        if now.saturating_sub(p.started) >= p.duration_secs {
+            p.status = AlgoStatus::Expired;
+            return AlgoStep::Stop;
+        }
+        let traded = traded_volume - p.last_volume.unwrap_or(traded_volume);
+        p.last_volume = Some(traded_volume);
+        let child_qty = p.next_child_qty(traded);
+        let next = now + p.interval_secs();
+        if child_qty <= 0 { return AlgoStep::Wait(next); }
+        let req = PlaceOrderReq {
+            trader: p.trader.clone(),
+            side: p.side,
+            price: p.price,
+            qty: child_qty,
+            leverage: p.leverage,
+            ttl_secs: p.child_ttl_secs,
+            is_limit: true,
+            display_qty: None,
+            stp_mode: None,
+            // children are tracked by id; a client id could clash with the trader's own
+            client_order_id: None,
+        };
+        AlgoStep::Place(req, next)
+    }
+
+    /// Record the outcome of a child placement. Returns the child id if the
+    /// parent was cancelled meanwhile and the child must be pulled again, and
+    /// whether the driver should keep going.
+    pub fn child_placed(&mut self, id: u64, qty: i128, result: Result<u64, String>) -> (Option<u64>, bool) {
+        let Some(p) = self.parents.get_mut(&id) else { return (None, false); };
+        match result {
+            Ok(child) => {
+                p.child_ids.push(child);
+                p.sent_qty += qty;
+                if p.status == AlgoStatus::Cancelled { return (Some(child), false); }
+                // completed once the children have filled, see `next_step`
+                (None, p.status == AlgoStatus::Running)
+            }
+            Err(e) => {
+                if p.status == AlgoStatus::Running {
+                    p.status = AlgoStatus::Failed;
+                    p.error = Some(e);
+                }
+                (None, false)
+            }
+        }
+    }
+
+    /// Stop slicing a trader's running parent; returns the children to pull from the book.
+    pub fn cancel(&mut self, trader: &str, id: u64) -> Option<Vec<u64>> {
+        match self.parents.get_mut(&id) {
+            Some(p) if p.trader == trader && p.status == AlgoStatus::Running => {
+                p.status = AlgoStatus::Cancelled;
+                Some(p.child_ids.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl AlgoParent {
+    fn interval_secs(&self) -> u64 {
+        match self.strategy {
+            AlgoStrategy::Twap { slice_secs } => slice_secs.unwrap_or(self.duration_secs / 10).max(1),
+            AlgoStrategy::Pov { .. } => 1,
+        }
+    }
+
+    /// Size of the next child. `traded` is market volume since the previous tick.
+    fn next_child_qty(&self, traded: i128) -> i128 {
+        let remaining = self.qty - self.sent_qty;
+        let qty = match self.strategy {
+            AlgoStrategy::Twap { .. } => {
+                let slices = (self.duration_secs / self.interval_secs()).max(1) as i128;
+                let left = (slices - self.child_ids.len() as i128).max(1);
+                // round up so the last slice does not leave a residue behind
+                (remaining + left - 1) / left
+            }
+            AlgoStrategy::Pov { participation_bps } => traded * participation_bps as i128 / 10_000,
+        };
+        qty.clamp(0, remaining)
+    }
+}
+
+// how often a waiting driver looks at the sequencer's clock
+const POLL: Duration = Duration::from_millis(200);
+
+/// Drive parent `id` until it is filled, cancelled or out of time. Slices
+/// are timed by the sequencer's clock, which may run faster than real time.
+pub fn spawn(seq: Sequencer, id: u64) {
+    tokio::spawn(async move {
+        loop {
+            let next = match seq.algo_next(id).await {
+                AlgoStep::Stop => return,
+                AlgoStep::Wait(next) => next,
+                AlgoStep::Place(req, next) => {
+                    let qty = req.qty;
+                    let result = seq.place_order(req).await.map(|r| r.id).map_err(|e| e.to_string());
+                    if !seq.algo_placed(id, qty, result).await { return; }
+                    next
+                }
+            };
+            while seq.now() < next { tokio::time::sleep(POLL).await; }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn twap(qty: i128) -> AlgoReq {
+        AlgoReq { trader: "alice".into(), side: engine::Side::Buy, price: 100, qty, leverage: 1, duration_secs: 20, child_ttl_secs: 60, strategy: AlgoKind::Twap, slice_secs: Some(10), participation_bps: None }
+    }
+
+    fn place(book: &mut AlgoBook, id: u64, now: u64, filled: i128, resting: bool) -> Option<i128> {
+        match book.next_step(id, now, 0, |_| (filled, resting)) {
+            AlgoStep::Place(req, _) => { book.child_placed(id, req.qty, Ok(book.parents[&id].child_ids.len() as u64 + 1)); Some(req.qty) }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_twap_completes_on_fills() {
+        let mut book = AlgoBook::default();
+        let id = book.insert(twap(10), 1_000);
+        assert_eq!(place(&mut book, id, 1_000, 0, false), Some(5));
+        assert_eq!(place(&mut book, id, 1_010, 0, true), Some(5));
+        // all sent but nothing filled: still running, and past the duration too
+        assert!(matches!(book.next_step(id, 1_030, 0, |_| (4, true)), AlgoStep::Wait(1_040)));
+        assert_eq!(book.parents[&id].status, AlgoStatus::Running);
+        assert!(matches!(book.next_step(id, 1_040, 0, |_| (10, false)), AlgoStep::Stop));
+        assert_eq!(book.parents[&id].status, AlgoStatus::Completed);
+        // out of time by the clock passed in, not the wall clock
+        let id = book.insert(twap(10), 1_000);
+        assert_eq!(place(&mut book, id, 1_000, 0, false), Some(5));
+        assert!(matches!(book.next_step(id, 1_020, 0, |_| (0, true)), AlgoStep::Stop));
+        assert_eq!(book.parents[&id].status, AlgoStatus::Expired);
+    }
+
+    #[test]
+    fn test_only_running_parents_cancel() {
+        let mut book = AlgoBook::default();
+        let id = book.insert(twap(10), 1_000);
+        assert_eq!(place(&mut book, id, 1_000, 0, false), Some(5));
+        assert!(book.cancel("bob", id).is_none());
+        assert_eq!(book.cancel("alice", id), Some(vec![1]));
+        assert!(book.cancel("alice", id).is_none());
+        let id = book.insert(twap(10), 1_000);
+        book.next_step(id, 1_000, 0, |_| (10, false));
+        assert!(book.cancel("alice", id).is_none());
+        assert_eq!(book.parents[&id].status, AlgoStatus::Completed);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(twap(10).validate().is_ok());
+        let field = |r: AlgoReq| match r.validate() { Err(ApiError::InvalidField { field, .. }) => field, other => panic!("{:?}", other) };
+        assert_eq!(field(AlgoReq { child_ttl_secs: 0, ..twap(10) }), "child_ttl_secs");
+        assert_eq!(field(AlgoReq { slice_secs: Some(0), ..twap(10) }), "slice_secs");
+        assert_eq!(field(AlgoReq { participation_bps: Some(100), ..twap(10) }), "participation_bps");
+        let pov = |bps| AlgoReq { strategy: AlgoKind::Pov, slice_secs: None, participation_bps: bps, ..twap(10) };
+        assert!(pov(Some(100)).validate().is_ok());
+        for bps in [None, Some(0), Some(10_001)] { assert_eq!(field(pov(bps)), "participation_bps"); }
+        assert_eq!(field(AlgoReq { slice_secs: Some(10), ..pov(Some(100)) }), "slice_secs");
+        let body = r#"{"trader":"alice","side":"buy","price":100,"qty":10,"leverage":1,"duration_secs":20,"strategy":"twap","slice_secs":5,"size":1}"#;
+        assert!(serde_json::from_str::<AlgoReq>(body).is_err());
+    }
+}
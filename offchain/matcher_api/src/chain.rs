@@ -7,9 +7,10 @@ abigen!(
     ZeroDayFutures,
     r#"[
         function ext_place_order(uint8 side, int256 price, int256 qty, uint32 leverage) external returns (uint64)
-        function ext_match(uint64 buy_id, uint64 sell_id, int256 price) external
+        function ext_match(uint64 buy_id, uint64 sell_id, int256 price, bool maker_is_buy, int128 maker_fee, int128 taker_fee) external
         function ext_update_oracle(uint64 product_id, int256 price) external
         function ext_deposit() external payable
+        event FeeAccrued(int128 maker_fee, uint128 taker_fee)
     ]"#
 );
 
@@ -76,11 +77,13 @@ impl ChainClient {
         Ok(None)
     }
 
-    pub async fn match_orders(&self, _buy_id: u64, _sell_id: u64, _price: i128) -> anyhow::Result<Option<String>> {
+    /// `maker_is_buy`: the buy order was resting first, so it pays the maker fee.
+    /// `fees` are what the engine charges, so both ledgers agree.
+    pub async fn match_orders(&self, _buy_id: u64, _sell_id: u64, _price: i128, _maker_is_buy: bool, _fees: engine::MatchFees) -> anyhow::Result<Option<String>> {
         #[cfg(feature = "onchain")]
         {
             if let Some(c) = &self.contract {
-                let call = c.ext_match(_buy_id, _sell_id, I256::from(_price));
+                let call = c.ext_match(_buy_id, _sell_id, I256::from(_price), _maker_is_buy, _fees.maker_fee, _fees.taker_fee);
                 let tx = call.send().await?;
                 let txh = tx.tx_hash();
                 return Ok(Some(format!("0x{}", hex::encode(txh.as_bytes()))));
@@ -102,6 +105,14 @@ impl ChainClient {
         Ok(None)
     }
 
+    /// Sum of every `FeeAccrued` the contract has emitted, net of rebates.
+    #[cfg(feature = "onchain")]
+    pub async fn fees_accrued(&self) -> anyhow::Result<Option<i128>> {
+        let Some(c) = &self.contract else { return Ok(None) };
+        let logs = c.event::<FeeAccruedFilter>().from_block(0u64).query().await?;
+        Ok(Some(logs.iter().map(|l| l.maker_fee + l.taker_fee as i128).sum()))
+    }
+
     pub async fn deposit(&self, _amount_wei: u128) -> anyhow::Result<Option<String>> {
         #[cfg(feature = "onchain")]
         {
@@ -0,0 +1,247 @@
+// Errors returned by the REST API. Every error goes out in one envelope:
+//
+//     {"error":{"code":4000,"name":"insufficient_collateral","message":"insufficient collateral","details":{...}}}
+//
+// `code` and `name` are stable and what clients should match on; `message`
+// is for humans and may change. `details` is only present when the error
+// carries data (e.g. the expected nonce). Codes are grouped by hundreds:
+// 1xxx the request itself, 2xxx authentication, 3xxx something that does not
+// exist, 4xxx refused by the exchange, 5xxx server faults.
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use engine::{RejectReason, Refusal};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ApiError {
+    /// Body, query string or path that does not parse.
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{field}: {reason}")]
+    InvalidField { field: &'static str, reason: String },
+    // signed orders only
+    #[cfg_attr(not(feature = "signing"), allow(dead_code))]
+    #[error("invalid signature: {0}")]
+    InvalidSignature(&'static str),
+    #[error("admin token required")]
+    AdminTokenRequired,
+    #[cfg_attr(not(feature = "signing"), allow(dead_code))]
+    #[error("signature does not match the trader")]
+    SignatureMismatch,
+    #[error("nonce mismatch, expected {expected}")]
+    NonceMismatch { expected: u64 },
+    #[error("account not found")]
+    AccountNotFound,
+    #[error("order not found")]
+    OrderNotFound,
+    #[error("algo not found")]
+    AlgoNotFound,
+    #[error("no open deficit")]
+    NoOpenDeficit,
+    #[error("insufficient collateral")]
+    InsufficientCollateral,
+    #[error("insufficient fees")]
+    InsufficientFees { balance: i128 },
+    /// Failed a pre-trade check; the order still got an id.
+    #[error("{reject}")]
+    OrderRejected { id: u64, reject: RejectReason },
+    #[error("account exists")]
+    AccountExists,
+    #[error("client_order_id in use")]
+    ClientOrderIdInUse { id: u64 },
+    #[error("account blocked by an unresolved deficit")]
+    AccountBlocked,
+    #[error("unknown collateral asset")]
+    UnknownAsset,
+    /// Fee, risk or collateral settings that fail validation.
+    #[error("{0}")]
+    InvalidConfig(String),
+    #[error("{0}")]
+    InvalidSubAccount(String),
+    #[error("{0}")]
+    InvalidTransfer(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+impl ApiError {
+    /// Stable numeric code.
+    pub fn code(&self) -> u32 {
+        use ApiError::*;
+        match self {
+            BadRequest(_) => 1000,
+            InvalidField { .. } => 1001,
+            InvalidSignature(_) => 1002,
+            AdminTokenRequired => 2000,
+            SignatureMismatch => 2001,
+            NonceMismatch { .. } => 2002,
+            AccountNotFound => 3000,
+            OrderNotFound => 3001,
+            AlgoNotFound => 3002,
+            NoOpenDeficit => 3003,
+            InsufficientCollateral => 4000,
+            InsufficientFees { .. } => 4001,
+            OrderRejected { .. } => 4002,
+            AccountExists => 4003,
+            ClientOrderIdInUse { .. } => 4004,
+            AccountBlocked => 4005,
+            UnknownAsset => 4006,
+            InvalidConfig(_) => 4007,
+            InvalidSubAccount(_) => 4008,
+            InvalidTransfer(_) => 4009,
+            Internal(_) => 5000,
+        }
+    }
+
+    /// Stable string code.
+    pub fn name(&self) -> &'static str {
+        use ApiError::*;
+        match self {
+            BadRequest(_) => "bad_request",
+            InvalidField { .. } => "invalid_field",
+            InvalidSignature(_) => "invalid_signature",
+            AdminTokenRequired => "admin_token_required",
+            SignatureMismatch => "signature_mismatch",
+            NonceMismatch { .. } => "nonce_mismatch",
+            AccountNotFound => "account_not_found",
+            OrderNotFound => "order_not_found",
+            AlgoNotFound => "algo_not_found",
+            NoOpenDeficit => "no_open_deficit",
+            InsufficientCollateral => "insufficient_collateral",
+            InsufficientFees { .. } => "insufficient_fees",
+            OrderRejected { .. } => "order_rejected",
+            AccountExists => "account_exists",
+            ClientOrderIdInUse { .. } => "client_order_id_in_use",
+            AccountBlocked => "account_blocked",
+            UnknownAsset => "unknown_asset",
+            InvalidConfig(_) => "invalid_config",
+            InvalidSubAccount(_) => "invalid_sub_account",
+            InvalidTransfer(_) => "invalid_transfer",
+            Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        use ApiError::*;
+        match self {
+            BadRequest(_) | InvalidField { .. } | InvalidSignature(_) | UnknownAsset | InvalidConfig(_) | InvalidSubAccount(_) | InvalidTransfer(_) => StatusCode::BAD_REQUEST,
+            AdminTokenRequired | SignatureMismatch => StatusCode::UNAUTHORIZED,
+            AccountBlocked => StatusCode::FORBIDDEN,
+            AccountNotFound | OrderNotFound | AlgoNotFound | NoOpenDeficit => StatusCode::NOT_FOUND,
+            NonceMismatch { .. } | AccountExists | ClientOrderIdInUse { .. } => StatusCode::CONFLICT,
+            InsufficientCollateral | InsufficientFees { .. } | OrderRejected { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        use ApiError::*;
+        match self {
+            InvalidField { field, .. } => Some(json!({"field": field})),
+            NonceMismatch { expected } => Some(json!({"expected": expected})),
+            InsufficientFees { balance } => Some(json!({"balance": balance})),
+            OrderRejected { id, reject } => Some(json!({"id": id, "reject": reject})),
+            ClientOrderIdInUse { id } => Some(json!({"id": id})),
+            _ => None,
+        }
+    }
+
+    pub fn invalid(field: &'static str, reason: impl Into<String>) -> Self {
+        ApiError::InvalidField { field, reason: reason.into() }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut body = json!({"code": self.code(), "name": self.name(), "message": self.to_string()});
+        if let Some(details) = self.details() { body["details"] = details; }
+        (self.status(), Json(json!({"error": body}))).into_response()
+    }
+}
+
+impl From<Refusal> for ApiError {
+    fn from(refusal: Refusal) -> Self {
+        use Refusal::*;
+        match refusal {
+            ClientOrderIdInUse { id } => ApiError::ClientOrderIdInUse { id },
+            OrderNotFound => ApiError::OrderNotFound,
+            InvalidAmount => ApiError::invalid("amount", "must be positive"),
+            InsufficientCollateral => ApiError::InsufficientCollateral,
+            NonceMismatch { expected } => ApiError::NonceMismatch { expected },
+            InsufficientFees { balance } => ApiError::InsufficientFees { balance },
+            InvalidFees { reason } | InvalidRiskLimits { reason } | InvalidCollateral { reason } => ApiError::InvalidConfig(reason),
+            AccountNotFound => ApiError::AccountNotFound,
+            AccountExists => ApiError::AccountExists,
+            UnknownAsset => ApiError::UnknownAsset,
+            InvalidSubAccount { reason } => ApiError::InvalidSubAccount(reason),
+            InvalidTransfer { reason } => ApiError::InvalidTransfer(reason),
+            AccountBlocked => ApiError::AccountBlocked,
+            NoOpenDeficit => ApiError::NoOpenDeficit,
+            // answered as a success by the order routes
+            Duplicate { id } => ApiError::Internal(format!("unexpected duplicate of order {}", id)),
+        }
+    }
+}
+
+/// `Json` that rejects with an `ApiError`.
+pub struct ApiJson<T>(pub T);
+
+#[axum::async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for ApiJson<T> {
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, ApiError> {
+        Json::<T>::from_request(req, state).await.map(|Json(v)| ApiJson(v)).map_err(|e: JsonRejection| ApiError::BadRequest(e.body_text()))
+    }
+}
+
+/// `Query` that rejects with an `ApiError`.
+pub struct ApiQuery<T>(pub T);
+
+#[axum::async_trait]
+impl<T: DeserializeOwned, S: Send + Sync> FromRequestParts<S> for ApiQuery<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ApiError> {
+        axum::extract::Query::<T>::from_request_parts(parts, state).await.map(|q| ApiQuery(q.0)).map_err(|e: QueryRejection| ApiError::BadRequest(e.body_text()))
+    }
+}
+
+/// `Path` that rejects with an `ApiError`.
+pub struct ApiPath<T>(pub T);
+
+#[axum::async_trait]
+impl<T: DeserializeOwned + Send, S: Send + Sync> FromRequestParts<S> for ApiPath<T> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, ApiError> {
+        axum::extract::Path::<T>::from_request_parts(parts, state).await.map(|p| ApiPath(p.0)).map_err(|e: PathRejection| ApiError::BadRequest(e.body_text()))
+    }
+}
+
+/// `Err(invalid_field)` unless `ok`.
+pub fn check(ok: bool, field: &'static str, reason: &str) -> Result<(), ApiError> {
+    if ok { Ok(()) } else { Err(ApiError::invalid(field, reason)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let res = ApiError::NonceMismatch { expected: 3 }.into_response();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let err = ApiError::from(Refusal::InvalidTransfer { reason: "amount must be positive".into() });
+        assert_eq!((err.code(), err.name(), err.to_string().as_str()), (4009, "invalid_transfer", "amount must be positive"));
+        let rejected = ApiError::OrderRejected { id: 7, reject: RejectReason::AccountNotFound };
+        assert_eq!(rejected.details(), Some(json!({"id": 7, "reject": {"code": "account_not_found"}})));
+        assert_eq!(rejected.to_string(), "account not found");
+    }
+}
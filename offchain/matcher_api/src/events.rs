@@ -0,0 +1,254 @@
+// Internal event bus. The sequencer publishes every state change as a typed
+// `ExchangeEvent` on one broadcast channel; WS clients, the logger and the
+// chain submitter each hold their own receiver. A consumer that falls behind
+// is told how many events it missed and skips ahead, the sequencer never
+// waits on it.
+use engine::EngineEvent;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
+
+use crate::algo::AlgoParent;
+
+pub type EventBus = broadcast::Sender<Published>;
+
+pub fn bus(capacity: usize) -> EventBus {
+    broadcast::channel(capacity.max(1)).0
+}
+
+pub use engine::{BookAction, CancelReason, OracleSource};
+
+/// Price series a candle is built from: trade prices or oracle marks.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CandleSource { #[default] Trades, Mark }
+
+/// WS subscription channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Channel {
+    #[serde(rename = "ticker")] Ticker,
+    #[serde(rename = "trades")] Trades,
+    #[serde(rename = "book.L2")] BookL2,
+    #[serde(rename = "book.L3")] BookL3,
+    #[serde(rename = "oracle")] Oracle,
+    #[serde(rename = "liquidations")] Liquidations,
+    #[serde(rename = "candles")] Candles,
+    /// Private: one stream per trader.
+    #[serde(rename = "account")] Account,
+}
+
+/// A sequenced stream: a channel, plus the trader for `account`.
+pub type Stream = (Channel, Option<String>);
+
+/// An event as it goes out on the bus, with its sequence number in every stream it belongs to.
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub event: ExchangeEvent,
+    pub seqs: Vec<(Stream, u64)>,
+}
+
+/// Last sequence number per stream. Numbers start at 1 and have no gaps, so a
+/// client that sees a jump has missed something and should resubscribe.
+#[derive(Default)]
+pub struct Sequences(HashMap<Stream, u64>);
+
+impl Sequences {
+    pub fn current(&self, stream: &Stream) -> u64 {
+        self.0.get(stream).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn stamp(&mut self, ev: &ExchangeEvent) -> Vec<(Stream, u64)> {
+        ev.streams().into_iter().map(|s| {
+            let n = self.0.entry(s.clone()).or_insert(0);
+            *n += 1;
+            (s, *n)
+        }).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Ticker {
+    pub best_bid: Option<i128>,
+    pub best_ask: Option<i128>,
+    pub last_price: Option<i128>,
+    pub mark: i128,
+    pub volume: i128, // cumulative matched qty
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ExchangeEvent {
+    OrderAccepted { id: u64, trader: String, client_order_id: Option<String>, side: engine::Side, price: i128, qty: i128 },
+    /// `code` and the fields after it say which pre-trade check failed.
+    OrderRejected {
+        id: u64,
+        trader: String,
+        client_order_id: Option<String>,
+        reason: String,
+        #[serde(flatten)]
+        reject: engine::RejectReason,
+    },
+    /// One side of a trade; `done` once nothing is left resting.
+    OrderFilled { id: u64, trader: String, price: i128, qty: i128, fee: i128, done: bool },
+    OrderCancelled { id: u64, trader: String, client_order_id: Option<String>, cancelled_qty: i128, reason: CancelReason },
+    // wire name kept from the original WS feed
+    #[serde(rename = "match")]
+    Trade {
+        #[serde(flatten)]
+        trade: engine::TradeExecution,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tx: Option<String>,
+    },
+    Oracle { symbol: String, price: i128, source: OracleSource },
+    Liquidation { trader: String, mark: i128, ts: u64 },
+    /// A liquidation left the account below zero; it is blocked until resolved.
+    Deficit {
+        #[serde(flatten)]
+        deficit: engine::Deficit,
+    },
+    DeficitResolved { trader: String, ids: Vec<u64> },
+    Deposit {
+        trader: String,
+        amount: i128,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        asset: Option<String>, // absent for the settlement currency
+    },
+    Withdrawal {
+        trader: String,
+        amount: i128,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        asset: Option<String>,
+    },
+    /// New fed price of a collateral asset.
+    CollateralPrice { asset: String, price: i128 },
+    SubAccountCreated { master: String, sub: String },
+    /// Collateral moved between two accounts under one master.
+    Transfer { from: String, to: String, amount: i128 },
+    SelfTradePrevented { trader: String, buy_id: u64, sell_id: u64, cancelled: Vec<u64> },
+    /// A collateral change and the balance after it.
+    Ledger {
+        #[serde(flatten)]
+        entry: engine::LedgerEntry,
+    },
+    /// Fees taken out of the treasury by an admin; not on any WS channel.
+    FeesSwept { amount: i128, balance: i128 },
+    /// Algo parent progress; sent whenever status, sent or filled qty changes.
+    Algo {
+        #[serde(flatten)]
+        parent: AlgoParent,
+        filled_qty: i128,
+    },
+    /// Best bid/ask, last trade and mark; sent when any of them changes.
+    Ticker(Ticker),
+    /// Aggregated levels that changed since the previous update; `qty` 0 removes the level.
+    L2Update { changes: Vec<L2Change> },
+    /// One resting order changed; `qty` is its visible size after the change, 0 once removed.
+    /// Names no trader or client order id.
+    L3Update { action: BookAction, id: u64, side: engine::Side, price: i128, qty: i128 },
+    /// Current state of a candle that changed since the last tick.
+    Candle {
+        source: CandleSource,
+        interval: engine::Interval,
+        #[serde(flatten)]
+        candle: engine::Candle,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct L2Change {
+    pub side: engine::Side,
+    pub price: i128,
+    pub qty: i128,
+}
+
+impl ExchangeEvent {
+    pub fn oracle(price: i128, source: OracleSource) -> Self {
+        ExchangeEvent::Oracle { symbol: "$singu".into(), price, source }
+    }
+
+    /// Bus form of an engine event; refusals changed nothing and are not published.
+    pub fn from_engine(ev: &EngineEvent) -> Option<Self> {
+        use ExchangeEvent::*;
+        Some(match ev.clone() {
+            EngineEvent::OrderAccepted { id, trader, client_order_id, side, price, qty } => OrderAccepted { id, trader, client_order_id, side, price, qty },
+            EngineEvent::OrderRejected { id, trader, client_order_id, reason, reject } => OrderRejected { id, trader, client_order_id, reason, reject },
+            EngineEvent::OrderFilled { id, trader, price, qty, fee, done } => OrderFilled { id, trader, price, qty, fee, done },
+            EngineEvent::BookChanged { action, id, side, price, qty } => L3Update { action, id, side, price, qty },
+            EngineEvent::OrderCancelled { id, trader, client_order_id, cancelled_qty, reason } => OrderCancelled { id, trader, client_order_id, cancelled_qty, reason },
+            EngineEvent::Trade { trade, tx } => Trade { trade, tx },
+            EngineEvent::Oracle { price, source } => ExchangeEvent::oracle(price, source),
+            EngineEvent::Liquidation { trader, mark, ts } => Liquidation { trader, mark, ts },
+            EngineEvent::Deficit { deficit } => Deficit { deficit },
+            EngineEvent::DeficitResolved { trader, ids } => DeficitResolved { trader, ids },
+            EngineEvent::Deposit { trader, amount, asset } => Deposit { trader, amount, asset },
+            EngineEvent::Withdrawal { trader, amount, asset } => Withdrawal { trader, amount, asset },
+            EngineEvent::CollateralPrice { asset, price } => CollateralPrice { asset, price },
+            EngineEvent::SubAccountCreated { master, sub } => SubAccountCreated { master, sub },
+            EngineEvent::Transfer { from, to, amount } => Transfer { from, to, amount },
+            EngineEvent::SelfTradePrevented { trader, buy_id, sell_id, cancelled } => SelfTradePrevented { trader, buy_id, sell_id, cancelled },
+            EngineEvent::Ledger { entry } => Ledger { entry },
+            EngineEvent::FeesSwept { amount, balance } => FeesSwept { amount, balance },
+            EngineEvent::Refused { .. } => return None,
+        })
+    }
+
+    /// Streams this event is published on.
+    pub fn streams(&self) -> Vec<Stream> {
+        use ExchangeEvent::*;
+        let account = |t: &str| (Channel::Account, Some(t.to_string()));
+        match self {
+            OrderAccepted { trader, .. } | OrderFilled { trader, .. } | OrderCancelled { trader, .. } => vec![account(trader)],
+            OrderRejected { trader, .. } | Deposit { trader, .. } | Withdrawal { trader, .. } | SelfTradePrevented { trader, .. } => vec![account(trader)],
+            Trade { .. } => vec![(Channel::Trades, None)],
+            Oracle { .. } | CollateralPrice { .. } => vec![(Channel::Oracle, None)],
+            Liquidation { trader, .. } => vec![(Channel::Liquidations, None), account(trader)],
+            Deficit { deficit } => vec![(Channel::Liquidations, None), account(&deficit.trader)],
+            DeficitResolved { trader, .. } => vec![account(trader)],
+            SubAccountCreated { master, sub } => vec![account(master), account(sub)],
+            Transfer { from, to, .. } => vec![account(from), account(to)],
+            Ledger { entry } => vec![account(&entry.trader)],
+            FeesSwept { .. } => vec![],
+            Algo { parent, .. } => vec![account(&parent.trader)],
+            Ticker(_) => vec![(Channel::Ticker, None)],
+            L2Update { .. } => vec![(Channel::BookL2, None)],
+            L3Update { .. } => vec![(Channel::BookL3, None)],
+            Candle { .. } => vec![(Channel::Candles, None)],
+        }
+    }
+}
+
+/// Next event for a subscriber, or `Err(missed)` if it fell behind. `None` once the bus is gone.
+pub async fn next(rx: &mut broadcast::Receiver<Published>) -> Option<Result<Published, u64>> {
+    match rx.recv().await {
+        Ok(p) => Some(Ok(p)),
+        Err(broadcast::error::RecvError::Lagged(missed)) => Some(Err(missed)),
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
+}
+
+/// Log every event; trades, liquidations and fee sweeps at info, the rest at debug.
+pub async fn log(mut rx: broadcast::Receiver<Published>) {
+    while let Some(p) = next(&mut rx).await {
+        match p {
+            Ok(Published { event: ev @ (ExchangeEvent::Trade { .. } | ExchangeEvent::Liquidation { .. } | ExchangeEvent::Deficit { .. } | ExchangeEvent::FeesSwept { .. }), .. }) => info!(target="arbz", "{}", serde_json::to_string(&ev).unwrap_or_default()),
+            Ok(p) => debug!(target="arbz", "{}", serde_json::to_string(&p.event).unwrap_or_default()),
+            Err(missed) => warn!(target="arbz", "event logger skipped {} events", missed),
+        }
+    }
+}
+
+/// Push admin oracle updates to the contract. Matches are settled on-chain
+/// inside the sequencer because they gate the book update.
+#[cfg(feature = "onchain")]
+pub async fn submit_to_chain(chain: crate::chain::ChainClient, mut rx: broadcast::Receiver<Published>) {
+    while let Some(p) = next(&mut rx).await {
+        match p {
+            Ok(Published { event: ExchangeEvent::Oracle { price, source: OracleSource::Admin, .. }, .. }) if chain.is_active() => {
+                if let Err(e) = chain.update_oracle(1, price).await { warn!(target="arbz", "oracle push failed: {}", e); }
+            }
+            Err(missed) => warn!(target="arbz", "chain submitter skipped {} events", missed),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// All mutable exchange state, owned by the sequencer task. Matching, margin
+// and liquidation are the engine's deterministic core (`engine::machine`);
+// this wraps it with what only the API needs: the event bus and its
+// sequence numbers, L2 diffs, candles, the ticker and algo parents.
+use engine::{Candles, EngineCommand, EngineConfig, EngineEvent, EngineState, Level, NewOrder, Side};
+use std::collections::HashMap;
+
+use crate::algo::{AlgoBook, AlgoStatus, AlgoStep};
+use crate::error::{check, ApiError};
+use crate::events::{EventBus, ExchangeEvent, L2Change, Published, Sequences, Ticker};
+use crate::{CancelOrderReq, PlaceOrderReq};
+
+/// `side` in request bodies: "buy" or "sell", any case; anything else fails to parse.
+pub mod side_name {
+    use engine::Side;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(side: &Side, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(match side { Side::Buy => "buy", Side::Sell => "sell" })
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Side, D::Error> {
+        parse(&String::deserialize(d)?).map_err(D::Error::custom)
+    }
+
+    pub fn parse(side: &str) -> Result<Side, String> {
+        if side.eq_ignore_ascii_case("buy") { Ok(Side::Buy) }
+        else if side.eq_ignore_ascii_case("sell") { Ok(Side::Sell) }
+        else { Err(format!("side must be \"buy\" or \"sell\", got {:?}", side)) }
+    }
+}
+
+pub struct ExchangeConfig {
+    pub engine: EngineConfig,
+    pub candle_retention: usize, // candles kept per interval and source
+}
+
+pub struct Exchange {
+    /// Book, accounts, positions, orders and trades. Only changed through `apply`.
+    pub engine: EngineState,
+    pub algos: AlgoBook, // TWAP/POV parents
+    pub cfg: ExchangeConfig,
+    pub seqs: Sequences, // per-stream sequence numbers of published events
+    pub(crate) last_ticker: Option<Ticker>,
+    /// Aggregated (bids, asks) as of the last `book.L2` update; what `GET /book` serves.
+    pub l2: (Vec<Level>, Vec<Level>),
+    pub trade_candles: Candles,
+    pub mark_candles: Candles,
+    // last (status, sent, filled) emitted per algo parent
+    pub(crate) last_algos: HashMap<u64, (AlgoStatus, i128, i128)>,
+    events: EventBus,
+}
+
+impl PlaceOrderReq {
+    /// Field checks that do not need exchange state; the rest is the engine's pre-trade pipeline.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        check(!self.trader.is_empty(), "trader", "must not be empty")?;
+        check(self.price > 0, "price", "must be positive")?;
+        check(self.qty > 0, "qty", "must be positive")?;
+        check(self.leverage > 0, "leverage", "must be at least 1")?;
+        check(self.ttl_secs > 0, "ttl_secs", "must be positive")?;
+        check(self.client_order_id.as_ref().map(|c| !c.is_empty() && c.len() <= 64).unwrap_or(true), "client_order_id", "must be 1 to 64 characters")
+    }
+
+    pub fn to_engine(&self, onchain: Option<(u64, String)>) -> NewOrder {
+        NewOrder {
+            trader: self.trader.clone(),
+            side: self.side,
+            price: self.price,
+            qty: self.qty,
+            leverage: self.leverage,
+            ttl_secs: self.ttl_secs,
+            is_limit: self.is_limit,
+            display_qty: self.display_qty,
+            stp_mode: self.stp_mode,
+            client_order_id: self.client_order_id.clone(),
+            onchain,
+        }
+    }
+}
+
+impl CancelOrderReq {
+    pub fn validate(&self) -> Result<(), ApiError> {
+        check(!self.trader.is_empty(), "trader", "must not be empty")?;
+        check(self.id.is_some() || self.client_order_id.is_some(), "id", "required unless client_order_id is given")?;
+        check(self.client_order_id.as_ref().map(|c| !c.is_empty()).unwrap_or(true), "client_order_id", "must not be empty")
+    }
+}
+
+impl Exchange {
+    pub fn new(cfg: ExchangeConfig, events: EventBus) -> Self {
+        Self {
+            // starts from the defaults; main applies `cfg.engine` as a logged command so replays see it
+            engine: EngineState::new(EngineConfig::default()),
+            algos: Default::default(),
+            trade_candles: Candles::new(cfg.candle_retention),
+            mark_candles: Candles::new(cfg.candle_retention),
+            cfg,
+            seqs: Default::default(),
+            last_ticker: None,
+            l2: Default::default(),
+            last_algos: Default::default(),
+            events,
+        }
+    }
+
+    // no subscribers is fine; the event is simply dropped
+    pub(crate) fn emit(&mut self, ev: ExchangeEvent) {
+        let seqs = self.seqs.stamp(&ev);
+        let _ = self.events.send(Published { event: ev, seqs });
+    }
+
+    /// Run a command through the engine at `now`, update candles and publish
+    /// the resulting events. Returns the engine events so the caller can
+    /// build its reply.
+    pub fn apply(&mut self, cmd: EngineCommand, now: u64) -> Vec<EngineEvent> {
+        let events = self.engine.apply(cmd, now);
+        for ev in &events {
+            match ev {
+                EngineEvent::Trade { trade, .. } => self.trade_candles.record(trade.ts, trade.price, trade.qty),
+                EngineEvent::Oracle { price, .. } => self.mark_candles.record(now, *price, 0),
+                _ => {}
+            }
+            if let Some(out) = ExchangeEvent::from_engine(ev) { self.emit(out); }
+        }
+        events
+    }
+
+    /// Publish the L2 levels that changed since the last call. The sequencer
+    /// runs this after every command, so `l2` and the `book.L2` seq always agree.
+    pub fn publish_book(&mut self) {
+        let bids = self.engine.book.depth(Side::Buy);
+        let asks = self.engine.book.depth(Side::Sell);
+        let mut changes = level_changes(Side::Buy, &self.l2.0, &bids);
+        changes.extend(level_changes(Side::Sell, &self.l2.1, &asks));
+        if changes.is_empty() { return; }
+        self.l2 = (bids, asks);
+        self.emit(ExchangeEvent::L2Update { changes });
+    }
+
+    /// Swap in recovered engine state; trade candles are rebuilt from its trades.
+    /// The state keeps only the latest mark, so mark candles restart from it:
+    /// marks before the snapshot are lost, those in the log tail are replayed.
+    pub fn restore(&mut self, state: EngineState) {
+        self.trade_candles = Candles::rebuild(self.cfg.candle_retention, state.trades.iter().map(|t| (t.ts, t.price, t.qty)));
+        self.mark_candles = Candles::rebuild(self.cfg.candle_retention, [(state.oracle.ts, state.oracle.price, 0)]);
+        self.engine = state;
+        self.l2 = Default::default();
+        self.publish_book();
+    }
+
+    // filled qty summed over an algo parent's children
+    pub fn algo_filled(&self, p: &crate::algo::AlgoParent) -> i128 {
+        algo_children(&self.engine, p).0
+    }
+
+    /// Next step for algo parent `id` at `now`.
+    pub fn algo_next(&mut self, id: u64, now: u64) -> AlgoStep {
+        let engine = &self.engine;
+        self.algos.next_step(id, now, engine.traded_volume, |p| algo_children(engine, p))
+    }
+
+    // parent as stored plus filled_qty
+    pub fn algo_view(&self, p: &crate::algo::AlgoParent) -> serde_json::Value {
+        let mut v = serde_json::to_value(p).unwrap_or_default();
+        v["filled_qty"] = serde_json::json!(self.algo_filled(p));
+        v
+    }
+}
+
+// filled qty over a parent's children, and whether any of them is still resting
+fn algo_children(engine: &EngineState, p: &crate::algo::AlgoParent) -> (i128, bool) {
+    let filled = p.child_ids.iter().filter_map(|id| engine.orders.get(id)).map(|r| r.filled_qty).sum();
+    (filled, p.child_ids.iter().any(|id| engine.book.get(*id).is_some()))
+}
+
+// levels that are new or changed in `new`, plus removed ones with qty 0
+fn level_changes(side: Side, old: &[Level], new: &[Level]) -> Vec<L2Change> {
+    let before: HashMap<i128, i128> = old.iter().map(|l| (l.price, l.qty)).collect();
+    let after: HashMap<i128, i128> = new.iter().map(|l| (l.price, l.qty)).collect();
+    let mut out: Vec<L2Change> = new.iter()
+        .filter(|l| before.get(&l.price) != Some(&l.qty))
+        .map(|l| L2Change { side, price: l.price, qty: l.qty })
+        .collect();
+    out.extend(old.iter().filter(|l| !after.contains_key(&l.price)).map(|l| L2Change { side, price: l.price, qty: 0 }));
+    out
+}
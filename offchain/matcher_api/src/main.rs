@@ -1,54 +1,188 @@
 use axum::{extract::State, routing::{get, post}, Json, Router};
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::routing::get_service;
 use tower_http::services::ServeDir;
 use axum::response::IntoResponse;
-use axum::extract::WebSocketUpgrade;
-use axum::response::Response;
-use axum::extract::ws::{Message, WebSocket};
-use engine::{Order, Side, Account, Position, OraclePrice};
+use engine::{OrderRecord, OrderStatus, Account, Position, StpMode, AcceleratedClock, Clock, SystemClock, FeeRates, FeeSchedule, FeeTier, CollateralAsset, PriceSource, RiskCheck, RiskLimits};
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
-use std::{collections::VecDeque, sync::{Arc, Mutex}};
 use tokio::net::TcpListener;
-use tracing::{info, warn};
+use tracing::info;
 mod chain;
+mod algo;
+mod error;
+mod events;
+mod exchange;
+mod matcher;
+mod sequencer;
+mod storage;
+mod wal;
+mod ws;
 use chain::ChainClient;
+use algo::AlgoReq;
+use error::{check, ApiError, ApiJson, ApiPath, ApiQuery};
+use events::EventBus;
+use exchange::{Exchange, ExchangeConfig};
+use sequencer::Sequencer;
 
 #[derive(Clone)]
 struct AppState { 
-    orderbook: Arc<Mutex<OrderBook>>,
-    accounts: Arc<Mutex<std::collections::HashMap<String, Account>>>,
-    positions: Arc<Mutex<std::collections::HashMap<String, Position>>>,
-    oracle: Arc<Mutex<OraclePrice>>, // single-product demo
-    fee_bps: Arc<Mutex<(u64,u64)>>, // (maker, taker)
+    seq: Sequencer, // owns book, accounts, positions, oracle, fees, nonces
     chain: ChainClient,
-    nonces: Arc<Mutex<std::collections::HashMap<String, u64>>>, // for signing demo
+    events: EventBus, // every state change, fanned out to WS clients and background consumers
+    admin_token: Option<String>, // required as `x-admin-token` on /admin routes when set
 }
 
-#[derive(Default)]
-struct OrderBook {
-    // store (on_chain_id, order)
-    buys: VecDeque<(u64, Order)>,
-    sells: VecDeque<(u64, Order)>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlaceOrderReq {
+    trader: String,
+    #[serde(with = "exchange::side_name")]
+    side: engine::Side,
+    price: i128, qty: i128, leverage: u32, ttl_secs: u64, is_limit: bool,
+    // iceberg: show at most this much of `qty` in the book at a time
+    #[serde(default)]
+    display_qty: Option<i128>,
+    // self-trade prevention; falls back to the account default, then cancel_newest
+    #[serde(default)]
+    stp_mode: Option<StpMode>,
+    // unique per trader; resubmitting it within the dedupe window returns the first order instead of placing a new one
+    #[serde(default)]
+    client_order_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListOrdersQuery {
+    trader: Option<String>,
+    status: Option<OrderStatus>,
+    // exclusive: return orders with id > cursor
+    cursor: Option<u64>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BookQuery { depth: Option<usize> }
+
+#[derive(Debug, Deserialize)]
+struct TradesQuery {
+    trader: Option<String>, // required for /fills
+    from: Option<u64>, // unix secs, inclusive
+    to: Option<u64>,
+    cursor: Option<u64>, // last trade id of the previous page
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LedgerQuery {
+    cursor: Option<u64>, // last seq of the previous page
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CandleQuery {
+    interval: engine::Interval,
+    #[serde(default)] source: events::CandleSource,
+    from: Option<u64>, // unix secs, inclusive, on candle start
+    to: Option<u64>,
+    limit: Option<usize>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CancelOrderReq { trader: String, #[serde(default)] id: Option<u64>, #[serde(default)] client_order_id: Option<String> }
 #[derive(Debug, Deserialize)]
-struct PlaceOrderReq { trader: String, side: String, price: i128, qty: i128, leverage: u32, ttl_secs: u64, is_limit: bool }
+#[serde(deny_unknown_fields)]
+struct DepositReq { trader: String, amount: i128, #[serde(default)] asset: Option<String> } // settlement currency if absent
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WithdrawReq { trader: String, amount: i128, #[serde(default)] asset: Option<String> }
+
 #[derive(Debug, Deserialize)]
-struct DepositReq { trader: String, amount: i128 }
+#[serde(deny_unknown_fields)]
+struct CollateralAssetReq { asset: String, haircut_bps: u64, source: PriceSource, #[serde(default)] price: i128 }
 
 #[derive(Debug, Deserialize)]
-struct WithdrawReq { trader: String, amount: i128 }
+#[serde(deny_unknown_fields)]
+struct CollateralPriceReq { asset: String, price: i128 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct OracleUpdateReq { price: i128 }
 
 #[derive(Debug, Deserialize)]
-struct FeeCfgReq { maker_bps: u64, taker_bps: u64 }
+#[serde(deny_unknown_fields)]
+struct FeeCfgReq { maker_bps: i64, taker_bps: i64 }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FeeOverrideReq { trader: String, #[serde(default)] maker_bps: Option<i64>, #[serde(default)] taker_bps: Option<i64> } // no rates: back to the schedule
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FeeScheduleReq { tiers: Vec<FeeTierReq> }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FeeTierReq { min_volume: i128, maker_bps: i64, taker_bps: i64 }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RiskLimitsReq {
+    checks: Vec<RiskCheck>,
+    max_leverage: u32,
+    price_band_bps: u64,
+    max_order_qty: i128,
+    max_open_orders: usize,
+    rate_limit: usize,
+    rate_window_secs: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SweepFeesReq { #[serde(default)] amount: Option<i128> } // everything if absent
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ResolveDeficitReq { trader: String }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AlgoCancelReq { trader: String, id: u64 }
+
+impl AlgoCancelReq {
+    fn validate(&self) -> Result<(), ApiError> {
+        check(!self.trader.is_empty(), "trader", "must not be empty")?;
+        check(self.id > 0, "id", "must be positive")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AlgoListQuery { trader: Option<String> }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StpDefaultReq { trader: String, mode: StpMode }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubAccountReq { master: String, sub: String }
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TransferReq { from: String, to: String, amount: i128 }
 
 
 #[derive(Debug, Serialize)]
-struct PlaceOrderResp { id: u64, tx: Option<String> }
+struct PlaceOrderResp {
+    id: u64,
+    tx: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_order_id: Option<String>,
+    // true when this was a retry answered from the dedupe window
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    duplicate: bool,
+}
 
 // Signed order support (feature gated for signing)
 #[cfg(feature = "signing")]
@@ -58,6 +192,7 @@ use ethers::types::transaction::eip712::{TypedData, Eip712};
 
 #[cfg(feature = "signing")]
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct SignedOrder {
     trader: EthAddress,
     side: String,
@@ -72,6 +207,7 @@ struct SignedOrder {
 
 #[cfg(feature = "signing")]
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct SignedOrderReq { order: SignedOrder, signature: String }
 
 #[tokio::main]
@@ -80,49 +216,119 @@ async fn main() {
     // Serve static files from this crate's static/ folder regardless of process CWD
     let static_dir = ServeDir::new(concat!(env!("CARGO_MANIFEST_DIR"), "/static"));
     // Build shared app state first so we can run background tasks (oracle jitter)
-    let app_state = AppState { 
-            orderbook: Default::default(),
-            accounts: Default::default(),
-            positions: Default::default(),
-            oracle: Arc::new(Mutex::new(OraclePrice{ price:100, conf:0, ts:0 })),
-            fee_bps: Arc::new(Mutex::new((2,5))),
-            chain: ChainClient::new(std::env::var("CONTRACT_ADDRESS").ok()),
-        nonces: Default::default(),
+    let events = events::bus(std::env::var("EVENT_BUS_CAPACITY").ok().and_then(|v| v.parse().ok()).unwrap_or(1024));
+    let chain = ChainClient::new(std::env::var("CONTRACT_ADDRESS").ok());
+    let cfg = ExchangeConfig {
+        engine: engine::EngineConfig {
+            client_id_window_secs: std::env::var("CLIENT_ID_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(60),
+            order_retention_secs: std::env::var("ORDER_RETENTION_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(86_400),
+            trade_retention: std::env::var("TRADE_RETENTION").ok().and_then(|v| v.parse().ok()).unwrap_or(100_000),
+        },
+        candle_retention: std::env::var("CANDLE_RETENTION").ok().and_then(|v| v.parse().ok()).unwrap_or(5_000),
+    };
+    let engine_cfg = cfg.engine.clone();
+    let mut ex = Exchange::new(cfg, events.clone());
+    // DATA_DIR holds the write-ahead log and snapshot; set it empty to run in memory only
+    let data_dir = std::env::var("DATA_DIR").unwrap_or_else(|_| "data".into());
+    let mut wal = if data_dir.is_empty() { None } else {
+        let every = std::env::var("SNAPSHOT_EVERY").ok().and_then(|v| v.parse().ok()).unwrap_or(1_000);
+        let (wal, recovered) = wal::Wal::open(&data_dir, every).expect("open write-ahead log");
+        let replayed = recovered.tail.len();
+        if let Some(state) = recovered.snapshot { ex.restore(state); }
+        // replay before anyone subscribes: these events were already published before the restart
+        for entry in recovered.tail { ex.apply(entry.cmd, entry.ts); }
+        ex.publish_book();
+        info!(target="arbz", "recovered state from {} ({} log entries replayed)", data_dir, replayed);
+        Some(wal)
+    };
+    // CLOCK_SPEED > 1 runs time that many times faster (simulations); it picks up where the recovered state left off
+    let speed: u64 = std::env::var("CLOCK_SPEED").ok().and_then(|v| v.parse().ok()).unwrap_or(1);
+    let clock: Arc<dyn Clock> = if speed > 1 {
+        Arc::new(AcceleratedClock::new(SystemClock.now().max(ex.engine.now), speed))
+    } else {
+        Arc::new(SystemClock)
+    };
+    // config changes go through the log too, so a replay sees the same windows
+    if ex.engine.cfg != engine_cfg {
+        let (cmd, now) = (engine::EngineCommand::Configure(engine_cfg), clock.now());
+        ex.apply(cmd.clone(), now);
+        if let Some(w) = wal.as_mut() { w.append(now, cmd).expect("append to write-ahead log"); }
+    }
+    // subscribe before the sequencer starts so nothing is missed
+    tokio::spawn(events::log(events.subscribe()));
+    #[cfg(feature = "onchain")]
+    tokio::spawn(events::submit_to_chain(chain.clone(), events.subscribe()));
+    #[cfg(feature = "sqlite")]
+    let history = std::env::var("SQLITE_PATH").ok().map(|path| {
+        let store = storage::SqliteStorage::open(&path).expect("open sqlite database");
+        info!(target="arbz", "writing history to {}", path);
+        (store, events.subscribe())
+    });
+    let seq = sequencer::start(ex, chain.clone(), wal, clock.clone());
+    #[cfg(feature = "sqlite")]
+    if let Some((store, rx)) = history {
+        let cfg = storage::StorageConfig {
+            flush_every: std::time::Duration::from_millis(std::env::var("STORAGE_FLUSH_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(500)),
+            max_batch: std::env::var("STORAGE_BATCH").ok().and_then(|v| v.parse().ok()).unwrap_or(1_000),
         };
+        tokio::spawn(storage::run(seq.clone(), rx, Box::new(store), cfg));
+    }
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    let app_state = AppState { seq, chain, events, admin_token };
     // Background: simple price jitter for $singu to mimic a live feed
     {
-        let st = app_state.clone();
+        let seq = app_state.seq.clone();
         tokio::spawn(async move {
             let mut dir: i128 = 1; // up or down
             let mut tick: u64 = 0;
             loop {
-                {
-                    
-                    let m = &st.oracle;
-                    let mut o = match m.lock() { Ok(g) => g, Err(e) => { warn!(target="arbz","Recovered from poisoned mutex: oracle"); e.into_inner() } };
-                    // simple 
-                    let step = 1 + ((tick % 3) as i128); // 1..3
-                    let next = o.price + dir * step;
-                    let clamped = next.clamp(50, 150);
-                    o.price = clamped;
-                    o.ts = o.ts.saturating_add(1);
-                    // occasionally flip direction
-                    if tick % 7 == 0 || clamped == 50 || clamped == 150 { dir = -dir; }
-                    tick = tick.wrapping_add(1);
-                }
+                let step = 1 + ((tick % 3) as i128); // 1..3
+                let clamped = seq.step_oracle(dir * step, 50, 150).await;
+                // occasionally flip direction
+                if tick % 7 == 0 || clamped == 50 || clamped == 150 { dir = -dir; }
+                tick = tick.wrapping_add(1);
                 tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
             }
         });
     }
+    // Background: the one matching loop, independent of WS connections
+    tokio::spawn(matcher::run(app_state.seq.clone()));
    
     let app = {
         let r = Router::new()
-            .route("/orders", post(place_order))
-            .route("/ws", get(ws))
+            .route("/orders", post(place_order).get(list_orders))
+            .route("/orders/cancel", post(cancel_order))
+            .route("/orders/:id", get(get_order))
+            .route("/orders/client/:trader/:client_order_id", get(get_order_by_client_id))
+            .route("/book", get(get_book))
+            .route("/candles", get(get_candles))
+            .route("/trades", get(list_trades))
+            .route("/fills", get(list_fills))
+            .route("/algos", post(place_algo).get(list_algos))
+            .route("/algos/cancel", post(cancel_algo))
+            .route("/algos/:id", get(get_algo))
+            .route("/ws", get(ws::ws))
             .route("/deposit", post(deposit))
             .route("/withdraw", post(withdraw))
             .route("/oracle", post(update_oracle))
-            .route("/fees", post(update_fees))
+            .route("/collateral", get(get_collateral))
+            .route("/fees", post(update_fees).get(get_fee_schedule))
+            .route("/risk", get(get_risk_limits))
+            .route("/accounts/stp", post(set_stp_default))
+            .route("/accounts/sub", post(create_sub_account))
+            .route("/transfer", post(transfer))
+            .route("/accounts/:trader/ledger", get(get_ledger))
+            .route("/accounts/:trader/fees", get(get_account_fees))
+            .route("/admin/fees", get(get_fees))
+            .route("/admin/fees/sweep", post(sweep_fees))
+            .route("/admin/fees/schedule", post(set_fee_schedule))
+            .route("/admin/fees/overrides", post(set_fee_override))
+            .route("/admin/collateral", post(set_collateral_asset))
+            .route("/admin/collateral/price", post(set_collateral_price))
+            .route("/admin/risk", post(set_risk_limits))
+            .route("/admin/deficits", get(get_deficits))
+            .route("/admin/deficits/resolve", post(resolve_deficit))
+            .route("/admin/account-token/:trader", get(get_account_token))
             .route("/status", get(status))
             .route("/state", get(get_state));
         #[cfg(feature = "signing")]
@@ -141,57 +347,140 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn place_order(State(state): State<AppState>, Json(req): Json<PlaceOrderReq>) -> impl IntoResponse {
-    let side = if req.side.eq_ignore_ascii_case("buy") { Side::Buy } else { Side::Sell };
-    let now = 0u64; // demo placeholder
-    let exp = now + req.ttl_secs;
-    let trader = req.trader.clone();
-    let order = Order { trader: trader.clone(), side, price: req.price, qty: req.qty, leverage: req.leverage, ts: now, expiry_ts: exp, is_limit: req.is_limit };
-    // by default create a local id; if on-chain returns an id, replace it
-    #[allow(unused_mut)]
-    let mut onchain_id: Option<u64> = None;
-    #[allow(unused_mut)]
-    let mut onchain_tx: Option<String> = None;
-    // lock margin for this order (simple: notional/leverage)
-    {
-        let mut accts = state.accounts.lock().unwrap();
-        let notional = (req.price.abs() as i128) * (req.qty.abs() as i128);
-        let margin = if req.leverage == 0 { notional } else { notional / (req.leverage as i128) };
-    accts.entry(trader)
-            .and_modify(|a| a.locked_margin += margin)
-            .or_insert(Account{ collateral: 0, locked_margin: margin });
-    }
-    // if on-chain is active, synchronously fetch id to rely on it
-    #[cfg(feature = "onchain")]
-    if state.chain.is_active() {
-        if let Ok(Some((oid, txh))) = state.chain.place_order(if req.side.eq_ignore_ascii_case("buy") {0} else {1}, req.price, req.qty, req.leverage).await {
-            onchain_id = Some(oid);
-            onchain_tx = Some(txh);
-        }
-    }
-    let final_id = onchain_id.unwrap_or_else(|| {
-        // fallback local id if on-chain inactive or failed
-        let ob = state.orderbook.lock().unwrap();
-        let next = (ob.buys.len() + ob.sells.len() + 1) as u64;
-        next
-    });
-    // push into book with the on-chain id (or fallback local id)
-    {
-        let mut ob = state.orderbook.lock().unwrap();
-        if matches!(order.side, Side::Buy) { ob.buys.push_back((final_id, order)); } else { ob.sells.push_back((final_id, order)); }
-    }
-    Json(PlaceOrderResp { id: final_id, tx: onchain_tx })
+async fn place_order(State(state): State<AppState>, ApiJson(req): ApiJson<PlaceOrderReq>) -> Result<Json<PlaceOrderResp>, ApiError> {
+    req.validate()?;
+    Ok(Json(state.seq.place_order(req).await?))
+}
+
+async fn cancel_order(State(state): State<AppState>, ApiJson(req): ApiJson<CancelOrderReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    req.validate()?;
+    Ok(Json(state.seq.cancel_order(req).await?))
+}
+
+async fn get_order(State(state): State<AppState>, ApiPath(id): ApiPath<u64>) -> Result<Json<OrderRecord>, ApiError> {
+    let rec = state.seq.read(move |ex| ex.engine.orders.get(&id).filter(|r| !expired(ex, r)).cloned()).await;
+    rec.map(Json).ok_or(ApiError::OrderNotFound)
+}
+
+// closed longer than the retention period as of the last command; pruned on the next placement
+fn expired(ex: &Exchange, r: &OrderRecord) -> bool {
+    r.closed_ts.map(|t| t.saturating_add(ex.engine.cfg.order_retention_secs) <= ex.engine.now).unwrap_or(false)
+}
+
+async fn list_orders(State(state): State<AppState>, ApiQuery(q): ApiQuery<ListOrdersQuery>) -> impl IntoResponse {
+    let limit = q.limit.unwrap_or(100).clamp(1, 1_000);
+    let page: Vec<OrderRecord> = state.seq.read(move |ex| ex.engine.orders
+        .range(q.cursor.map(|c| c.saturating_add(1)).unwrap_or(0)..)
+        .map(|(_, r)| r)
+        .filter(|r| !expired(ex, r))
+        .filter(|r| q.trader.as_ref().map(|t| &r.trader == t).unwrap_or(true))
+        .filter(|r| q.status.map(|s| r.status == s).unwrap_or(true))
+        .take(limit)
+        .cloned()
+        .collect()).await;
+    let next_cursor = if page.len() == limit { page.last().map(|r| r.id) } else { None };
+    Json(serde_json::json!({"orders": page, "next_cursor": next_cursor}))
+}
+
+async fn get_order_by_client_id(State(state): State<AppState>, ApiPath((trader, client_order_id)): ApiPath<(String, String)>) -> Result<Json<OrderRecord>, ApiError> {
+    let id = state.seq.read(move |ex| ex.engine.client_order(&trader, &client_order_id)).await;
+    get_order(State(state), ApiPath(id.ok_or(ApiError::OrderNotFound)?)).await
+}
+
+async fn get_book(State(state): State<AppState>, ApiQuery(q): ApiQuery<BookQuery>) -> impl IntoResponse {
+    let depth = q.depth.unwrap_or(20).clamp(1, 500);
+    // seq of the last book.L2 update already reflected here; apply updates with a higher seq on top
+    let (seq, bids, asks) = state.seq.read(move |ex| {
+        let (bids, asks) = &ex.l2;
+        (ex.seqs.current(&(events::Channel::BookL2, None)), bids.iter().take(depth).copied().collect::<Vec<_>>(), asks.iter().take(depth).copied().collect::<Vec<_>>())
+    }).await;
+    Json(serde_json::json!({"symbol":"$singu","seq":seq,"bids":bids,"asks":asks}))
+}
+
+// trades matching the filters after `cursor`, oldest first, plus the next cursor
+fn trade_page(ex: &Exchange, q: &TradesQuery) -> (Vec<engine::TradeExecution>, Option<u64>) {
+    let limit = q.limit.unwrap_or(100).clamp(1, 1_000);
+    let after = q.cursor.unwrap_or(0);
+    let start = ex.engine.trades.partition_point(|t| t.id <= after);
+    let page: Vec<engine::TradeExecution> = ex.engine.trades.range(start..)
+        .filter(|t| q.trader.as_ref().map(|w| t.involves(w)).unwrap_or(true))
+        .filter(|t| q.from.map(|f| t.ts >= f).unwrap_or(true))
+        .take_while(|t| q.to.map(|to| t.ts <= to).unwrap_or(true))
+        .take(limit)
+        .cloned()
+        .collect();
+    let next_cursor = if page.len() == limit { page.last().map(|t| t.id) } else { None };
+    (page, next_cursor)
+}
+
+async fn list_trades(State(state): State<AppState>, ApiQuery(q): ApiQuery<TradesQuery>) -> impl IntoResponse {
+    let (trades, next_cursor) = state.seq.read(move |ex| trade_page(ex, &q)).await;
+    Json(serde_json::json!({"trades": trades, "next_cursor": next_cursor}))
+}
+
+async fn list_fills(State(state): State<AppState>, ApiQuery(q): ApiQuery<TradesQuery>) -> Result<Json<serde_json::Value>, ApiError> {
+    let trader = q.trader.clone().ok_or_else(|| ApiError::invalid("trader", "required"))?;
+    let (trades, next_cursor) = state.seq.read(move |ex| trade_page(ex, &q)).await;
+    let fills: Vec<engine::Fill> = trades.iter().flat_map(|t| t.fills_for(&trader)).collect();
+    Ok(Json(serde_json::json!({"fills": fills, "next_cursor": next_cursor})))
+}
+
+async fn get_ledger(State(state): State<AppState>, ApiPath(trader): ApiPath<String>, ApiQuery(q): ApiQuery<LedgerQuery>) -> Result<Json<serde_json::Value>, ApiError> {
+    let limit = q.limit.unwrap_or(100).clamp(1, 1_000);
+    // balance and entries from the same point in the sequence; the balance is the sum of all entries
+    let page = state.seq.read(move |ex| {
+        let acc = ex.engine.accounts.get(&trader)?;
+        let assets = ex.engine.asset_balances.get(&trader).cloned().unwrap_or_default();
+        Some((trader.clone(), acc.collateral, assets, ex.engine.ledger.page(&trader, q.cursor.unwrap_or(0), limit).to_vec()))
+    }).await;
+    let (trader, balance, assets, entries) = page.ok_or(ApiError::AccountNotFound)?;
+    let next_cursor = if entries.len() == limit { entries.last().map(|e| e.seq) } else { None };
+    Ok(Json(serde_json::json!({"trader": trader, "balance": balance, "assets": assets, "entries": entries, "next_cursor": next_cursor})))
+}
+
+async fn get_candles(State(state): State<AppState>, ApiQuery(q): ApiQuery<CandleQuery>) -> impl IntoResponse {
+    let limit = q.limit.unwrap_or(500).clamp(1, 1_000);
+    let (from, to) = (q.from.unwrap_or(0), q.to.unwrap_or(u64::MAX));
+    // most recent `limit` candles in the range, oldest first
+    let candles: Vec<engine::Candle> = state.seq.read(move |ex| {
+        let series = match q.source { events::CandleSource::Trades => &ex.trade_candles, events::CandleSource::Mark => &ex.mark_candles };
+        let all: Vec<engine::Candle> = series.range(q.interval, from, to).copied().collect();
+        all[all.len().saturating_sub(limit)..].to_vec()
+    }).await;
+    Json(serde_json::json!({"interval":q.interval,"source":q.source,"candles":candles}))
+}
+
+async fn place_algo(State(state): State<AppState>, ApiJson(req): ApiJson<AlgoReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    req.validate()?;
+    let id = state.seq.place_algo(req).await;
+    algo::spawn(state.seq.clone(), id);
+    Ok(Json(serde_json::json!({"id":id})))
+}
+
+async fn get_algo(State(state): State<AppState>, ApiPath(id): ApiPath<u64>) -> Result<Json<serde_json::Value>, ApiError> {
+    let view = state.seq.read(move |ex| ex.algos.parents.get(&id).map(|p| ex.algo_view(p))).await;
+    view.map(Json).ok_or(ApiError::AlgoNotFound)
+}
+
+async fn list_algos(State(state): State<AppState>, ApiQuery(q): ApiQuery<AlgoListQuery>) -> impl IntoResponse {
+    let out: Vec<serde_json::Value> = state.seq.read(move |ex| ex.algos.parents.values()
+        .filter(|p| q.trader.as_ref().map(|t| &p.trader == t).unwrap_or(true))
+        .map(|p| ex.algo_view(p))
+        .collect()).await;
+    Json(serde_json::json!({"algos": out}))
+}
+
+async fn cancel_algo(State(state): State<AppState>, ApiJson(req): ApiJson<AlgoCancelReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    req.validate()?;
+    let cancelled = state.seq.cancel_algo(req.trader, req.id).await.ok_or(ApiError::AlgoNotFound)?;
+    Ok(Json(serde_json::json!({"ok":true,"id":req.id,"cancelled_children":cancelled})))
 }
 
 #[cfg(feature = "signing")]
-async fn place_signed_order(State(state): State<AppState>, Json(req): Json<SignedOrderReq>) -> Response {
+async fn place_signed_order(State(state): State<AppState>, ApiJson(req): ApiJson<SignedOrderReq>) -> Result<Json<PlaceOrderResp>, ApiError> {
+    let side = exchange::side_name::parse(&req.order.side).map_err(|e| ApiError::invalid("side", e))?;
     // 1. Check nonce
-    {
-        let mut nonces = state.nonces.lock().unwrap();
-        let cur = nonces.get(&format!("{:?}", req.order.trader)).cloned().unwrap_or(0);
-        if req.order.nonce != cur { return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error":"bad nonce","expected":cur}))).into_response(); }
-        nonces.insert(format!("{:?}", req.order.trader), cur + 1);
-    }
+    state.seq.consume_nonce(format!("{:?}", req.order.trader), req.order.nonce).await.map_err(|expected| ApiError::NonceMismatch { expected })?;
     // 2. Recreate digest per EIP-712 using TypedData
     let td_json = serde_json::json!({
         "types": {
@@ -228,198 +517,195 @@ async fn place_signed_order(State(state): State<AppState>, Json(req): Json<Signe
             "nonce": req.order.nonce
         }
     });
-    let typed: TypedData = match serde_json::from_value(td_json) { Ok(v) => v, Err(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error":"typed data"}))).into_response() };
-    let digest: H256 = match typed.encode_eip712() { Ok(h) => H256::from(h), Err(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error":"encode failed"}))).into_response() };
+    let typed: TypedData = serde_json::from_value(td_json).map_err(|_| ApiError::InvalidSignature("typed data"))?;
+    let digest = H256::from(typed.encode_eip712().map_err(|_| ApiError::InvalidSignature("encode failed"))?);
     // 3. Parse signature using ethers::core::types::Signature
-    let sig_bytes = match hex::decode(req.signature.trim_start_matches("0x")) { Ok(b) => b, Err(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error":"bad sig hex"}))).into_response() };
-    if sig_bytes.len() != 65 { return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error":"len"}))).into_response(); }
-    let sig = match Signature::try_from(sig_bytes.as_slice()) { Ok(s) => s, Err(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error":"sig parse"}))).into_response() };
-    let recovered_addr = match sig.recover(digest) { Ok(a) => a, Err(_) => return (StatusCode::BAD_REQUEST, Json(serde_json::json!({"error":"recover"}))).into_response() };
-    if recovered_addr != req.order.trader { return (StatusCode::UNAUTHORIZED, Json(serde_json::json!({"error":"signature mismatch"}))).into_response(); }
+    let sig_bytes = hex::decode(req.signature.trim_start_matches("0x")).map_err(|_| ApiError::InvalidSignature("not hex"))?;
+    check(sig_bytes.len() == 65, "signature", "must be 65 bytes")?;
+    let sig = Signature::try_from(sig_bytes.as_slice()).map_err(|_| ApiError::InvalidSignature("does not parse"))?;
+    let recovered_addr = sig.recover(digest).map_err(|_| ApiError::InvalidSignature("cannot recover signer"))?;
+    if recovered_addr != req.order.trader { return Err(ApiError::SignatureMismatch); }
     // 4. Convert to internal PlaceOrderReq and delegate
-    let inner = PlaceOrderReq { trader: format!("{:?}", req.order.trader), side: req.order.side.clone(), price: req.order.price, qty: req.order.qty, leverage: req.order.leverage, ttl_secs: req.order.ttl_secs, is_limit: req.order.is_limit };
-    place_order(State(state), Json(inner)).await.into_response()
+    let inner = PlaceOrderReq { trader: format!("{:?}", req.order.trader), side, price: req.order.price, qty: req.order.qty, leverage: req.order.leverage, ttl_secs: req.order.ttl_secs, is_limit: req.order.is_limit, display_qty: None, stp_mode: None, client_order_id: None };
+    place_order(State(state), ApiJson(inner)).await
 }
 
-async fn ws(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
-    ws.on_upgrade(|socket| async move { handle_ws(state, socket).await })
+async fn deposit(State(state): State<AppState>, ApiJson(req): ApiJson<DepositReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    check(!req.trader.is_empty(), "trader", "must not be empty")?;
+    check(req.amount > 0, "amount", "must be positive")?;
+    state.seq.deposit(req.trader, req.amount, req.asset).await?;
+    Ok(Json(serde_json::json!({"ok":true})))
 }
 
-async fn handle_ws(state: AppState, mut socket: WebSocket) {
-    // Track last price sent to avoid spamming identical oracle events
-    let mut last_mark: Option<i128> = None;
-    // local helper to recover from poisoned mutexes without panicking
-    fn lock<'a, T>(m: &'a Mutex<T>, name: &str) -> std::sync::MutexGuard<'a, T> {
-        match m.lock() {
-            Ok(g) => g,
-            Err(e) => {
-                warn!(target = "arbz", "Recovered from poisoned mutex: {}", name);
-                e.into_inner()
-            }
-        }
-    }
-    loop {
-        // Always read current oracle price; if changed, emit an oracle tick event
-        let current_mark = { lock(&state.oracle, "oracle").price };
-        if last_mark.map(|p| p != current_mark).unwrap_or(true) {
-            let tick = serde_json::json!({
-                "event": "oracle",
-                "symbol": "$singu",
-                "price": current_mark
-            });
-            if socket.send(Message::Text(tick.to_string())).await.is_err() { break; }
-            last_mark = Some(current_mark);
-        }
-        let (buy_opt, sell_opt) = {
-            let ob = lock(&state.orderbook, "orderbook");
-            (ob.buys.front().cloned(), ob.sells.front().cloned())
-        };
-        if let (Some((buy_id, buy)), Some((sell_id, sell))) = (buy_opt, sell_opt) {
-            let price = (buy.price + sell.price) / 2;
-            let qty = buy.qty.min(sell.qty);
-            // fee calc and position update (toy)
-            let (maker_bps, taker_bps) = *lock(&state.fee_bps, "fee_bps");
-            let notional = (price.abs() as i128) * (qty.abs() as i128);
-            let maker_fee = notional * maker_bps as i128 / 10_000;
-            let taker_fee = notional * taker_bps as i128 / 10_000;
-            // book-keeping to accounts and positions (do not hold locks across await)
-            {
-                let mut accts = lock(&state.accounts, "accounts");
-                accts.entry(buy.trader.clone()).and_modify(|a| a.collateral -= taker_fee).or_insert(Account{collateral: -taker_fee, locked_margin:0});
-                accts.entry(sell.trader.clone()).and_modify(|a| a.collateral -= maker_fee).or_insert(Account{collateral: -maker_fee, locked_margin:0});
-            }
-            {
-                let mut pos = lock(&state.positions, "positions");
-                // buyer long +qty at price
-                let pb = pos.entry(buy.trader.clone()).or_insert(Position{ trader: buy.trader.clone(), entry_price: price, qty: 0, leverage: buy.leverage, margin: 0, opened_ts: 0, expiry_ts: 86_400 });
-                let new_qty_b = pb.qty + qty;
-                if new_qty_b == 0 {
-                    pb.entry_price = 0; // flat position
-                    pb.qty = 0;
-                } else if pb.qty == 0 {
-                    pb.entry_price = price;
-                    pb.qty = new_qty_b;
-                } else {
-                    // weighted average price
-                    pb.entry_price = (pb.entry_price * pb.qty + price * qty) / new_qty_b;
-                    pb.qty = new_qty_b;
-                }
-                // seller short -qty at price
-                let ps = pos.entry(sell.trader.clone()).or_insert(Position{ trader: sell.trader.clone(), entry_price: price, qty: 0, leverage: sell.leverage, margin: 0, opened_ts: 0, expiry_ts: 86_400 });
-                let new_qty_s = ps.qty - qty;
-                if new_qty_s == 0 {
-                    ps.entry_price = 0;
-                    ps.qty = 0;
-                } else if ps.qty == 0 {
-                    ps.entry_price = price;
-                    ps.qty = new_qty_s;
-                } else {
-                    ps.entry_price = (ps.entry_price * ps.qty + price * (-qty)) / new_qty_s;
-                    ps.qty = new_qty_s;
-                }
-            }
-            #[allow(unused_mut)]
-            let mut obj = serde_json::json!({"event":"match","price":price,"qty":qty,"buy_trader":buy.trader,"sell_trader":sell.trader,"maker_fee":maker_fee,"taker_fee":taker_fee,"buy_id":buy_id,"sell_id":sell_id});
-            // in on-chain mode, only match when chain is active and call succeeds; otherwise keep orders queued
-            #[allow(unused_mut)]
-            let mut matched_ok = true; // set false if on-chain fails
-            #[cfg(feature = "onchain")]
-            {
-                if state.chain.is_active() {
-                    match state.chain.match_orders(buy_id, sell_id, price).await {
-                        Ok(Some(txh)) => { obj["tx"] = serde_json::json!(txh); matched_ok = true; }
-                        Ok(None) => { matched_ok = false; }
-                        Err(_) => { matched_ok = false; }
-                    }
-                } else {
-                    matched_ok = false;
-                }
-            }
-            if matched_ok {
-                // here pop from the book only after successful match 
-                {
-                    let mut ob = lock(&state.orderbook, "orderbook");
-                    ob.buys.pop_front();
-                    ob.sells.pop_front();
-                }
-                if socket.send(Message::Text(obj.to_string())).await.is_err() { break; }
-            } else {
-                // chain inactive or match failed, retry?
-                tokio::time::sleep(std::time::Duration::from_millis(300)).await;
-                continue;
-            }
+async fn withdraw(State(state): State<AppState>, ApiJson(req): ApiJson<WithdrawReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    check(!req.trader.is_empty(), "trader", "must not be empty")?;
+    check(req.amount > 0, "amount", "must be positive")?;
+    state.seq.withdraw(req.trader, req.amount, req.asset).await?;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
 
-            // simple liquidation checks for both traders using current oracle price
-            let mark = current_mark;
-            for who in [buy.trader, sell.trader] {
-                let (qty_w, entry_w) = {
-                    let pos = lock(&state.positions, "positions");
-                    if let Some(p) = pos.get(&who) { (p.qty, p.entry_price) } else { (0, 0) }
-                };
-                if qty_w != 0 {
-                    let pnl = (mark - entry_w) * qty_w; // here short if qty negative
-                    let (collateral, locked) = {
-                        let ac = lock(&state.accounts, "accounts");
-                        if let Some(a) = ac.get(&who) { (a.collateral, a.locked_margin) } else { (0,0) }
-                    };
-                    if locked > 0 {
-                        let equity = collateral + pnl - locked;
-                        let health_bps = if locked == 0 { i128::MAX } else { (equity * 10_000) / locked };
-                        if health_bps < 5_000 { // thrhdolf 50%
-                            
-                            {
-                                let mut ac = lock(&state.accounts, "accounts");
-                                if let Some(a) = ac.get_mut(&who) { a.collateral += pnl; a.locked_margin = 0; }
-                            }
-                            {
-                                let mut pos = lock(&state.positions, "positions");
-                                if let Some(p) = pos.get_mut(&who) { p.qty = 0; }
-                            }
-                            let lmsg = serde_json::json!({"event":"liquidation","trader":who,"mark":mark});
-                            if socket.send(Message::Text(lmsg.to_string())).await.is_err() { break; }
-                        }
-                    }
-                }
-            }
-        }
-        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
-    }
+async fn get_collateral(State(state): State<AppState>) -> impl IntoResponse {
+    let (mark, assets) = state.seq.read(|ex| (ex.engine.oracle.price, ex.engine.collateral_assets.clone())).await;
+    // `price` is what the asset is valued at right now, the mark for `mark`-priced assets
+    let assets: serde_json::Map<String, serde_json::Value> = assets.into_iter().map(|(name, c)| {
+        let price = c.price(mark);
+        (name, serde_json::json!({"haircut_bps": c.haircut_bps, "source": c.source, "price": price, "price_ts": c.price_ts}))
+    }).collect();
+    Json(serde_json::json!({"settlement": engine::SETTLEMENT_ASSET, "assets": assets}))
+}
+
+async fn set_collateral_asset(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<CollateralAssetReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let config = CollateralAsset { haircut_bps: req.haircut_bps, source: req.source, price: req.price, price_ts: 0 };
+    state.seq.set_collateral_asset(req.asset, config).await?;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
+
+async fn set_collateral_price(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<CollateralPriceReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    state.seq.set_collateral_price(req.asset, req.price).await?;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
+
+async fn update_oracle(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<OracleUpdateReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    check(req.price > 0, "price", "must be positive")?;
+    // the on-chain push is done by the chain submitter subscribed to the event bus
+    state.seq.set_oracle(req.price).await;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
+
+// flat rates for everyone
+async fn update_fees(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<FeeCfgReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    state.seq.set_fees(FeeSchedule::flat(req.maker_bps, req.taker_bps)).await.map_err(ApiError::InvalidConfig)?;
+    Ok(Json(serde_json::json!({"ok":true})))
 }
 
-async fn deposit(State(state): State<AppState>, Json(req): Json<DepositReq>) -> impl IntoResponse {
-    fn lock<'a, T>(m: &'a Mutex<T>, name: &str) -> std::sync::MutexGuard<'a, T> { match m.lock() { Ok(g) => g, Err(e) => { warn!(target="arbz","Recovered from poisoned mutex: {}", name); e.into_inner() } } }
-    let mut a = lock(&state.accounts, "accounts");
-    a.entry(req.trader).and_modify(|x| x.collateral += req.amount).or_insert(Account{collateral:req.amount, locked_margin:0});
-    Json(serde_json::json!({"ok":true}))
+async fn get_fee_schedule(State(state): State<AppState>) -> impl IntoResponse {
+    let fees = state.seq.read(|ex| ex.engine.fees.clone()).await;
+    Json(serde_json::json!({"tiers": fees.tiers, "window_days": engine::VOLUME_WINDOW_DAYS}))
 }
 
-async fn withdraw(State(state): State<AppState>, Json(req): Json<WithdrawReq>) -> impl IntoResponse {
-    fn lock<'a, T>(m: &'a Mutex<T>, name: &str) -> std::sync::MutexGuard<'a, T> { match m.lock() { Ok(g) => g, Err(e) => { warn!(target="arbz","Recovered from poisoned mutex: {}", name); e.into_inner() } } }
-    let mut a = lock(&state.accounts, "accounts");
-    let ok = if let Some(acc) = a.get_mut(&req.trader) { if acc.collateral - acc.locked_margin >= req.amount { acc.collateral -= req.amount; true } else { false } } else { false };
-    Json(serde_json::json!({"ok":ok}))
+async fn get_account_fees(State(state): State<AppState>, ApiPath(trader): ApiPath<String>) -> Result<Json<serde_json::Value>, ApiError> {
+    let rates = state.seq.read(move |ex| ex.engine.accounts.contains_key(&trader).then(|| (ex.engine.fee_rates(&trader), trader))).await;
+    let ((volume, tier, rates), trader) = rates.ok_or(ApiError::AccountNotFound)?;
+    Ok(Json(serde_json::json!({"trader": trader, "volume_30d": volume, "tier": tier, "override": tier.is_none(), "maker_bps": rates.maker_bps, "taker_bps": rates.taker_bps})))
 }
 
-async fn update_oracle(State(state): State<AppState>, Json(req): Json<OracleUpdateReq>) -> impl IntoResponse {
-    fn lock<'a, T>(m: &'a Mutex<T>, name: &str) -> std::sync::MutexGuard<'a, T> { match m.lock() { Ok(g) => g, Err(e) => { warn!(target="arbz","Recovered from poisoned mutex: {}", name); e.into_inner() } } }
-    let mut o = lock(&state.oracle, "oracle");
-    o.price = req.price; o.ts += 1;
+// admin routes are open unless ADMIN_TOKEN is set
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(token) = state.admin_token.as_ref() else { return Ok(()) };
+    if headers.get("x-admin-token").and_then(|v| v.to_str().ok()) == Some(token.as_str()) { Ok(()) } else { Err(ApiError::AdminTokenRequired) }
+}
+
+// the token a trader subscribes to their own `account` stream with
+async fn get_account_token(State(state): State<AppState>, headers: HeaderMap, ApiPath(trader): ApiPath<String>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let admin = state.admin_token.as_deref().ok_or_else(|| ApiError::InvalidConfig("ADMIN_TOKEN is not set, so account streams are closed".into()))?;
+    let t = trader.clone();
+    if !state.seq.read(move |ex| ex.engine.accounts.contains_key(&t)).await { return Err(ApiError::AccountNotFound); }
+    Ok(Json(serde_json::json!({"trader": trader, "token": ws::account_token(admin, &trader)})))
+}
+
+async fn get_fees(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let (treasury, ledger_fees, trade_fees, trades_complete, overrides) = state.seq.read(|ex| {
+        let e = &ex.engine;
+        // charged to traders, from their ledgers (never pruned) and from the retained trades
+        let ledger_fees: i128 = e.ledger.traders().flat_map(|t| e.ledger.entries(t)).filter(|x| x.kind == engine::LedgerKind::Fee).map(|x| -x.amount).sum();
+        let trade_fees: i128 = e.trades.iter().map(|t| t.maker_fee + t.taker_fee).sum();
+        let complete = e.trades.front().map(|t| t.id == 1).unwrap_or(e.last_trade_id == 0);
+        (e.treasury.clone(), ledger_fees, trade_fees, complete, e.fee_overrides.clone())
+    }).await;
+    let ok = ledger_fees == treasury.accrued && (!trades_complete || trade_fees == treasury.accrued);
+    #[allow(unused_mut)]
+    let mut out = serde_json::json!({
+        "balance": treasury.balance, "accrued": treasury.accrued, "swept": treasury.swept, "absorbed": treasury.absorbed,
+        "reconciliation": {"ledger_fees": ledger_fees, "trade_fees": trade_fees, "trades_complete": trades_complete, "ok": ok},
+        "overrides": overrides,
+    });
     #[cfg(feature = "onchain")]
-    {
-        if state.chain.is_active() {
-            let _ = tokio::spawn({ let cc = state.clone(); let p = req.price; async move { let _ = cc.chain.update_oracle(1, p).await; } });
-        }
+    if let Ok(Some(total)) = state.chain.fees_accrued().await {
+        out["reconciliation"]["onchain_accrued"] = serde_json::json!(total);
     }
-    Json(serde_json::json!({"ok":true}))
+    Ok(Json(out))
+}
+
+async fn sweep_fees(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<SweepFeesReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    if let Some(amount) = req.amount { check(amount > 0, "amount", "must be positive")?; }
+    let (amount, balance) = state.seq.sweep_fees(req.amount).await.map_err(|balance| ApiError::InsufficientFees { balance })?;
+    Ok(Json(serde_json::json!({"ok":true,"amount":amount,"balance":balance})))
 }
 
-async fn update_fees(State(state): State<AppState>, Json(req): Json<FeeCfgReq>) -> impl IntoResponse {
-    fn lock<'a, T>(m: &'a Mutex<T>, name: &str) -> std::sync::MutexGuard<'a, T> { match m.lock() { Ok(g) => g, Err(e) => { warn!(target="arbz","Recovered from poisoned mutex: {}", name); e.into_inner() } } }
-    *lock(&state.fee_bps, "fee_bps") = (req.maker_bps, req.taker_bps);
-    Json(serde_json::json!({"ok":true}))
+async fn set_fee_schedule(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<FeeScheduleReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let tiers = req.tiers.into_iter().map(|t| FeeTier { min_volume: t.min_volume, rates: FeeRates { maker_bps: t.maker_bps, taker_bps: t.taker_bps } }).collect();
+    state.seq.set_fees(FeeSchedule { tiers }).await.map_err(ApiError::InvalidConfig)?;
+    Ok(Json(serde_json::json!({"ok":true})))
 }
 
-async fn status(State(_state): State<AppState>) -> impl IntoResponse {
+async fn set_fee_override(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<FeeOverrideReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    check(!req.trader.is_empty(), "trader", "must not be empty")?;
+    let rates = match (req.maker_bps, req.taker_bps) {
+        (Some(maker_bps), Some(taker_bps)) => Some(FeeRates { maker_bps, taker_bps }),
+        (None, None) => None,
+        (None, Some(_)) => return Err(ApiError::invalid("maker_bps", "required with taker_bps")),
+        (Some(_), None) => return Err(ApiError::invalid("taker_bps", "required with maker_bps")),
+    };
+    state.seq.set_fee_override(req.trader, rates).await.map_err(ApiError::InvalidConfig)?;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
+
+async fn get_risk_limits(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.seq.read(|ex| ex.engine.risk_limits.clone()).await)
+}
+
+async fn set_risk_limits(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<RiskLimitsReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let RiskLimitsReq { checks, max_leverage, price_band_bps, max_order_qty, max_open_orders, rate_limit, rate_window_secs } = req;
+    state.seq.set_risk_limits(RiskLimits { checks, max_leverage, price_band_bps, max_order_qty, max_open_orders, rate_limit, rate_window_secs }).await?;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
+
+async fn get_deficits(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let deficits = state.seq.read(|ex| ex.engine.deficits.clone()).await;
+    let blocked: std::collections::BTreeSet<&str> = deficits.iter().filter(|d| d.resolved_ts.is_none()).map(|d| d.trader.as_str()).collect();
+    let uncovered: i128 = deficits.iter().map(|d| d.uncovered).sum();
+    Ok(Json(serde_json::json!({"blocked": blocked, "uncovered": uncovered, "deficits": deficits})))
+}
+
+async fn resolve_deficit(State(state): State<AppState>, headers: HeaderMap, ApiJson(req): ApiJson<ResolveDeficitReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let ids = state.seq.resolve_deficit(req.trader.clone()).await?;
+    Ok(Json(serde_json::json!({"ok":true,"trader":req.trader,"resolved":ids})))
+}
+
+async fn create_sub_account(State(state): State<AppState>, ApiJson(req): ApiJson<SubAccountReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    check(!req.master.is_empty(), "master", "must not be empty")?;
+    check(!req.sub.is_empty(), "sub", "must not be empty")?;
+    state.seq.create_sub_account(req.master.clone(), req.sub.clone()).await?;
+    Ok(Json(serde_json::json!({"ok":true,"master":req.master,"sub":req.sub})))
+}
+
+// between a master and its sub-accounts, or two sub-accounts of one master
+async fn transfer(State(state): State<AppState>, ApiJson(req): ApiJson<TransferReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    check(!req.from.is_empty(), "from", "must not be empty")?;
+    check(!req.to.is_empty(), "to", "must not be empty")?;
+    state.seq.transfer(req.from, req.to, req.amount).await?;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
+
+async fn set_stp_default(State(state): State<AppState>, ApiJson(req): ApiJson<StpDefaultReq>) -> Result<Json<serde_json::Value>, ApiError> {
+    check(!req.trader.is_empty(), "trader", "must not be empty")?;
+    state.seq.set_stp_default(req.trader, req.mode).await;
+    Ok(Json(serde_json::json!({"ok":true})))
+}
+
+async fn status(#[allow(unused_variables)] State(state): State<AppState>) -> impl IntoResponse {
     #[cfg(feature = "onchain")]
     {
         let active = state.chain.is_active();
@@ -442,16 +728,37 @@ struct TraderView {
     pnl: i64,
     health_bps: Option<i64>,
     nonce: u64,
+    collateral_value: i64, // settlement balance plus other assets after haircuts
+    free_collateral: i64,
+    #[serde(skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    assets: std::collections::BTreeMap<String, i128>, // non-settlement collateral balances
+    #[serde(skip_serializing_if = "Option::is_none")]
+    master: Option<String>, // set on sub-accounts
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    blocked: bool, // unresolved deficit: no orders, withdrawals or outgoing transfers
+}
+
+/// A master and its sub-accounts added up. Margin and liquidation stay per account.
+#[derive(Serialize)]
+struct MasterView {
+    master: String,
+    subs: Vec<String>,
+    collateral: i64,
+    collateral_value: i64,
+    locked_margin: i64,
+    pnl: i64,
+    equity: i64,
 }
 
 fn clamp_i128_to_i64(v: i128) -> i64 {
     if v > i64::MAX as i128 { i64::MAX } else if v < i64::MIN as i128 { i64::MIN } else { v as i64 }
 }
 
-fn compute_health_and_pnl(acc: &Account, pos: Option<&Position>, mark: i128) -> (i64, Option<i64>) {
+// `collateral_value` is in settlement currency after haircuts
+fn compute_health_and_pnl(collateral_value: i128, acc: &Account, pos: Option<&Position>, mark: i128) -> (i64, Option<i64>) {
     let (qty, entry) = if let Some(p) = pos { (p.qty, p.entry_price) } else { (0,0) };
     let pnl_i128 = (mark - entry) * qty;
-    let equity_i128 = acc.collateral + pnl_i128 - acc.locked_margin;
+    let equity_i128 = collateral_value + pnl_i128 - acc.locked_margin;
     let pnl = clamp_i128_to_i64(pnl_i128);
     let health_bps = if acc.locked_margin == 0 {
         None
@@ -463,29 +770,50 @@ fn compute_health_and_pnl(acc: &Account, pos: Option<&Position>, mark: i128) ->
 }
 
 async fn get_state(State(state): State<AppState>) -> impl IntoResponse {
-    fn lock<'a, T>(m: &'a Mutex<T>, name: &str) -> std::sync::MutexGuard<'a, T> { match m.lock() { Ok(g) => g, Err(e) => { warn!(target="arbz","Recovered from poisoned mutex: {}", name); e.into_inner() } } }
-    let mark = { lock(&state.oracle, "oracle").price };
-    let accounts = lock(&state.accounts, "accounts");
-    let positions = lock(&state.positions, "positions");
-    let nonces = lock(&state.nonces, "nonces");
-    let mut out: Vec<TraderView> = Vec::new();
-    for (tr, acc) in accounts.iter() {
-        let pos = positions.get(tr);
-        let (pnl, hbps) = compute_health_and_pnl(acc, pos, mark);
-        let (qty_i128, entry_i128) = pos.map(|p| (p.qty, p.entry_price)).unwrap_or((0,0));
-        let qty = clamp_i128_to_i64(qty_i128);
-        let entry_price = clamp_i128_to_i64(entry_i128);
-        let nonce = *nonces.get(tr).unwrap_or(&0);
-        out.push(TraderView{
-            trader: tr.clone(),
-            collateral: clamp_i128_to_i64(acc.collateral),
-            locked_margin: clamp_i128_to_i64(acc.locked_margin),
-            qty,
-            entry_price,
-            pnl,
-            health_bps: hbps,
-            nonce,
-        });
-    }
-    Json(serde_json::json!({"mark":mark, "traders": out}))
+    // one read so accounts, positions and nonces come from the same point in the sequence
+    let (mark, out, masters) = state.seq.read(|ex| {
+        let mark = ex.engine.oracle.price;
+        let mut out: Vec<TraderView> = Vec::new();
+        for (tr, acc) in ex.engine.accounts.iter() {
+            let pos = ex.engine.positions.get(tr);
+            let value = ex.engine.collateral_value(tr);
+            let (pnl, hbps) = compute_health_and_pnl(value, acc, pos, mark);
+            let (qty_i128, entry_i128) = pos.map(|p| (p.qty, p.entry_price)).unwrap_or((0,0));
+            let qty = clamp_i128_to_i64(qty_i128);
+            let entry_price = clamp_i128_to_i64(entry_i128);
+            let nonce = *ex.engine.nonces.get(tr).unwrap_or(&0);
+            out.push(TraderView{
+                trader: tr.clone(),
+                collateral: clamp_i128_to_i64(acc.collateral),
+                locked_margin: clamp_i128_to_i64(acc.locked_margin),
+                qty,
+                entry_price,
+                pnl,
+                health_bps: hbps,
+                nonce,
+                collateral_value: clamp_i128_to_i64(value),
+                free_collateral: clamp_i128_to_i64(ex.engine.free_collateral(tr)),
+                assets: ex.engine.asset_balances.get(tr).cloned().unwrap_or_default(),
+                master: ex.engine.masters.get(tr).cloned(),
+                blocked: ex.engine.is_blocked(tr),
+            });
+        }
+        // roll up every master that has sub-accounts
+        let mut masters: Vec<MasterView> = Vec::new();
+        for master in ex.engine.accounts.keys().filter(|t| !ex.engine.masters.contains_key(*t)) {
+            let subs: Vec<String> = ex.engine.subs_of(master).cloned().collect();
+            if subs.is_empty() { continue; }
+            let (mut collateral, mut value, mut locked, mut pnl) = (0i128, 0i128, 0i128, 0i128);
+            for t in std::iter::once(master).chain(subs.iter()) {
+                let Some(acc) = ex.engine.accounts.get(t) else { continue };
+                collateral += acc.collateral;
+                value += ex.engine.collateral_value(t);
+                locked += acc.locked_margin;
+                pnl += ex.engine.positions.get(t).map(|p| (mark - p.entry_price) * p.qty).unwrap_or(0);
+            }
+            masters.push(MasterView { master: master.clone(), subs, collateral: clamp_i128_to_i64(collateral), collateral_value: clamp_i128_to_i64(value), locked_margin: clamp_i128_to_i64(locked), pnl: clamp_i128_to_i64(pnl), equity: clamp_i128_to_i64(value + pnl - locked) });
+        }
+        (mark, out, masters)
+    }).await;
+    Json(serde_json::json!({"mark":mark, "traders": out, "masters": masters}))
 }
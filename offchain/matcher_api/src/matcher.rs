@@ -0,0 +1,49 @@
+// The matching clock. `run` is the one background driver started from main;
+// it asks the sequencer to tick (the engine's liquidation sweep and matching),
+// so nothing here depends on WS connections. `end_tick` publishes the views
+// derived from a tick.
+use crate::events::{CandleSource, ExchangeEvent, Ticker};
+use crate::exchange::Exchange;
+use crate::sequencer::Sequencer;
+
+pub async fn run(seq: Sequencer) {
+    loop {
+        seq.tick().await;
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    }
+}
+
+impl Exchange {
+    /// Emit the ticker, touched candles and progress for algo parents if they changed.
+    pub fn end_tick(&mut self) {
+        let candles: Vec<ExchangeEvent> = self.trade_candles.take_updates().into_iter().map(|(interval, candle)| ExchangeEvent::Candle { source: CandleSource::Trades, interval, candle })
+            .chain(self.mark_candles.take_updates().into_iter().map(|(interval, candle)| ExchangeEvent::Candle { source: CandleSource::Mark, interval, candle }))
+            .collect();
+        for ev in candles { self.emit(ev); }
+        let ticker = self.ticker();
+        if self.last_ticker.as_ref() != Some(&ticker) {
+            self.last_ticker = Some(ticker.clone());
+            self.emit(ExchangeEvent::Ticker(ticker));
+        }
+        let mut changed = Vec::new();
+        for p in self.algos.parents.values() {
+            let filled_qty = self.algo_filled(p);
+            let key = (p.status, p.sent_qty, filled_qty);
+            if self.last_algos.get(&p.id) != Some(&key) { changed.push((key, ExchangeEvent::Algo { parent: p.clone(), filled_qty })); }
+        }
+        for (key, ev) in changed {
+            if let ExchangeEvent::Algo { parent, .. } = &ev { self.last_algos.insert(parent.id, key); }
+            self.emit(ev);
+        }
+    }
+
+    pub fn ticker(&self) -> Ticker {
+        Ticker {
+            best_bid: self.engine.book.buys.iter().map(|(_, o)| o.price).max(),
+            best_ask: self.engine.book.sells.iter().map(|(_, o)| o.price).min(),
+            last_price: self.engine.last_price,
+            mark: self.engine.oracle.price,
+            volume: self.engine.traded_volume,
+        }
+    }
+}
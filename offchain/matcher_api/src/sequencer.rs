@@ -0,0 +1,305 @@
+// Single-threaded sequencer. One task owns the `Exchange` and applies
+// commands from a channel one at a time, so every update is atomic and all
+// events come out in one total order. Handlers hold a cheap `Sequencer`
+// handle and await the reply. State changes go through the engine as
+// `EngineCommand`s stamped with the time from `clock`; with a `Wal`, every
+// one that changed state is logged before its reply is sent.
+use engine::{Clock, CollateralAsset, EngineCommand, EngineEvent, FeeRates, FeeSchedule, OracleSource, Refusal, RiskLimits, StpMode};
+use std::sync::Arc;
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use crate::algo::{AlgoReq, AlgoStep};
+use crate::chain::ChainClient;
+use crate::error::ApiError;
+use crate::exchange::Exchange;
+use crate::wal::Wal;
+use crate::{CancelOrderReq, PlaceOrderReq, PlaceOrderResp};
+
+type Reply<T> = oneshot::Sender<T>;
+
+pub enum Command {
+    PlaceOrder(PlaceOrderReq, Reply<Result<PlaceOrderResp, ApiError>>),
+    CancelOrder(CancelOrderReq, Reply<Result<serde_json::Value, ApiError>>),
+    Deposit { trader: String, amount: i128, asset: Option<String>, reply: Reply<Result<(), Refusal>> },
+    Withdraw { trader: String, amount: i128, asset: Option<String>, reply: Reply<Result<(), Refusal>> },
+    SetCollateralAsset { asset: String, config: CollateralAsset, reply: Reply<Result<(), Refusal>> },
+    SetCollateralPrice { asset: String, price: i128, reply: Reply<Result<(), Refusal>> },
+    SetOracle(i128, Reply<()>),
+    StepOracle { delta: i128, min: i128, max: i128, reply: Reply<i128> },
+    /// Err(reason) if the schedule is invalid.
+    SetFees { schedule: FeeSchedule, reply: Reply<Result<(), String>> },
+    SetFeeOverride { trader: String, rates: Option<FeeRates>, reply: Reply<Result<(), String>> },
+    SetRiskLimits { limits: RiskLimits, reply: Reply<Result<(), Refusal>> },
+    SetStpDefault { trader: String, mode: StpMode, reply: Reply<()> },
+    CreateSubAccount { master: String, sub: String, reply: Reply<Result<(), Refusal>> },
+    Transfer { from: String, to: String, amount: i128, reply: Reply<Result<(), Refusal>> },
+    #[cfg(feature = "signing")]
+    ConsumeNonce { trader: String, nonce: u64, reply: Reply<Result<(), u64>> },
+    /// Ok((swept, balance left)), or Err(balance) if there is not that much.
+    SweepFees { amount: Option<i128>, reply: Reply<Result<(i128, i128), i128>> },
+    ResolveDeficit { trader: String, reply: Reply<Result<Vec<u64>, Refusal>> },
+    PlaceAlgo(AlgoReq, Reply<u64>),
+    AlgoNext(u64, Reply<AlgoStep>),
+    AlgoPlaced { id: u64, qty: i128, result: Result<u64, String>, reply: Reply<bool> },
+    CancelAlgo { trader: String, id: u64, reply: Reply<Option<Vec<u64>>> },
+    /// Run matching and the per-tick risk sweep.
+    Tick(Reply<()>),
+    /// Read-only access for queries; runs between commands so it sees a consistent state.
+    Read(Box<dyn FnOnce(&Exchange) + Send>),
+}
+
+#[derive(Clone)]
+pub struct Sequencer {
+    tx: mpsc::Sender<Command>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Spawn the sequencer task owning `ex`.
+pub fn start(ex: Exchange, chain: ChainClient, wal: Option<Wal>, clock: Arc<dyn Clock>) -> Sequencer {
+    let (tx, rx) = mpsc::channel(4096);
+    tokio::spawn(run(ex, chain, wal, clock.clone(), rx));
+    Sequencer { tx, clock }
+}
+
+// Log before acking. A command we cannot make durable must not be acknowledged,
+// and memory is already ahead of the log, so stop the process.
+fn log(wal: &mut Option<Wal>, ex: &Exchange, ts: u64, cmd: EngineCommand) {
+    let Some(w) = wal.as_mut() else { return };
+    let res = w.append(ts, cmd).and_then(|_| if w.snapshot_due() { w.snapshot(ex.engine.clone()) } else { Ok(()) });
+    if let Err(e) = res {
+        error!(target="arbz", "write-ahead log failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+// Apply through the exchange and log the command unless it changed nothing.
+fn exec(ex: &mut Exchange, wal: &mut Option<Wal>, cmd: EngineCommand, now: u64) -> Vec<EngineEvent> {
+    let mark = ex.engine.last_mark;
+    let events = ex.apply(cmd.clone(), now);
+    // refused commands and idle ticks are not logged
+    let idle = match &cmd {
+        EngineCommand::Tick { .. } => events.is_empty() && ex.engine.last_mark == mark,
+        _ => matches!(events.first(), Some(EngineEvent::Refused { .. })),
+    };
+    if !idle { log(wal, ex, now, cmd); }
+    events
+}
+
+// pull one resting order; used for algo children
+fn cancel(ex: &mut Exchange, wal: &mut Option<Wal>, trader: &str, id: u64, now: u64) -> Option<u64> {
+    let cmd = EngineCommand::CancelOrder { trader: trader.to_string(), id: Some(id), client_order_id: None };
+    match exec(ex, wal, cmd, now).first() {
+        Some(EngineEvent::OrderCancelled { id, .. }) => Some(*id),
+        _ => None,
+    }
+}
+
+fn refused(events: &[EngineEvent]) -> Result<(), Refusal> {
+    match events.first() {
+        Some(EngineEvent::Refused { refusal }) => Err(refusal.clone()),
+        _ => Ok(()),
+    }
+}
+
+fn fee_result(events: &[EngineEvent]) -> Result<(), String> {
+    match events.first() {
+        Some(EngineEvent::Refused { refusal: Refusal::InvalidFees { reason } }) => Err(reason.clone()),
+        _ => Ok(()),
+    }
+}
+
+fn place_reply(events: &[EngineEvent], req: &PlaceOrderReq, tx: Option<String>) -> Result<PlaceOrderResp, ApiError> {
+    match events.first() {
+        Some(EngineEvent::OrderAccepted { id, client_order_id, .. }) => Ok(PlaceOrderResp { id: *id, tx, client_order_id: client_order_id.clone(), duplicate: false }),
+        Some(EngineEvent::Refused { refusal: Refusal::Duplicate { id } }) => Ok(PlaceOrderResp { id: *id, tx: None, client_order_id: req.client_order_id.clone(), duplicate: true }),
+        Some(EngineEvent::Refused { refusal }) => Err(refusal.clone().into()),
+        Some(EngineEvent::OrderRejected { id, reject, .. }) => Err(ApiError::OrderRejected { id: *id, reject: reject.clone() }),
+        other => Err(ApiError::Internal(format!("unexpected engine result {:?}", other))),
+    }
+}
+
+async fn run(mut ex: Exchange, #[allow(unused_variables)] chain: ChainClient, mut wal: Option<Wal>, clock: Arc<dyn Clock>, mut rx: mpsc::Receiver<Command>) {
+    while let Some(cmd) = rx.recv().await {
+        let mutates = !matches!(cmd, Command::Read(_));
+        let now = clock.now();
+        match cmd {
+            Command::PlaceOrder(req, reply) => {
+                #[allow(unused_mut)]
+                let mut onchain: Option<(u64, String)> = None;
+                // if on-chain is active, synchronously fetch id to rely on it (not for rejects and retries)
+                #[cfg(feature = "onchain")]
+                if chain.is_active() && ex.engine.would_place(&req.to_engine(None), now) {
+                    if let Ok(Some(v)) = chain.place_order(if req.side == engine::Side::Buy {0} else {1}, req.price, req.qty, req.leverage).await {
+                        onchain = Some(v);
+                    }
+                }
+                let tx = onchain.as_ref().map(|(_, tx)| tx.clone());
+                let events = exec(&mut ex, &mut wal, EngineCommand::PlaceOrder(req.to_engine(onchain)), now);
+                let _ = reply.send(place_reply(&events, &req, tx));
+            }
+            Command::CancelOrder(req, reply) => {
+                let cmd = EngineCommand::CancelOrder { trader: req.trader, id: req.id, client_order_id: req.client_order_id };
+                let res = match exec(&mut ex, &mut wal, cmd, now).first() {
+                    Some(EngineEvent::OrderCancelled { id, client_order_id, cancelled_qty, .. }) => Ok(json!({"ok":true,"id":id,"client_order_id":client_order_id,"cancelled_qty":cancelled_qty})),
+                    _ => Err(ApiError::OrderNotFound),
+                };
+                let _ = reply.send(res);
+            }
+            Command::Deposit { trader, amount, asset, reply } => {
+                let res = refused(&exec(&mut ex, &mut wal, EngineCommand::Deposit { trader, amount, asset }, now));
+                let _ = reply.send(res);
+            }
+            Command::Withdraw { trader, amount, asset, reply } => {
+                let res = refused(&exec(&mut ex, &mut wal, EngineCommand::Withdraw { trader, amount, asset }, now));
+                let _ = reply.send(res);
+            }
+            Command::SetCollateralAsset { asset, config, reply } => {
+                let res = refused(&exec(&mut ex, &mut wal, EngineCommand::SetCollateralAsset { asset, config }, now));
+                let _ = reply.send(res);
+            }
+            Command::SetCollateralPrice { asset, price, reply } => {
+                let res = refused(&exec(&mut ex, &mut wal, EngineCommand::SetCollateralPrice { asset, price }, now));
+                let _ = reply.send(res);
+            }
+            Command::SetOracle(price, reply) => {
+                exec(&mut ex, &mut wal, EngineCommand::SetOracle { price, source: OracleSource::Admin }, now);
+                let _ = reply.send(());
+            }
+            Command::StepOracle { delta, min, max, reply } => {
+                let price = (ex.engine.oracle.price + delta).clamp(min, max);
+                exec(&mut ex, &mut wal, EngineCommand::SetOracle { price, source: OracleSource::Feed }, now);
+                let _ = reply.send(price);
+            }
+            Command::SetFees { schedule, reply } => {
+                let res = fee_result(&exec(&mut ex, &mut wal, EngineCommand::SetFeeSchedule(schedule), now));
+                let _ = reply.send(res);
+            }
+            Command::SetFeeOverride { trader, rates, reply } => {
+                let res = fee_result(&exec(&mut ex, &mut wal, EngineCommand::SetFeeOverride { trader, rates }, now));
+                let _ = reply.send(res);
+            }
+            Command::SetRiskLimits { limits, reply } => {
+                let res = refused(&exec(&mut ex, &mut wal, EngineCommand::SetRiskLimits(limits), now));
+                let _ = reply.send(res);
+            }
+            Command::SetStpDefault { trader, mode, reply } => {
+                exec(&mut ex, &mut wal, EngineCommand::SetStpDefault { trader, mode }, now);
+                let _ = reply.send(());
+            }
+            Command::CreateSubAccount { master, sub, reply } => {
+                let res = refused(&exec(&mut ex, &mut wal, EngineCommand::CreateSubAccount { master, sub }, now));
+                let _ = reply.send(res);
+            }
+            Command::Transfer { from, to, amount, reply } => {
+                let res = refused(&exec(&mut ex, &mut wal, EngineCommand::Transfer { from, to, amount }, now));
+                let _ = reply.send(res);
+            }
+            #[cfg(feature = "signing")]
+            Command::ConsumeNonce { trader, nonce, reply } => {
+                let res = match exec(&mut ex, &mut wal, EngineCommand::ConsumeNonce { trader, nonce }, now).first() {
+                    Some(EngineEvent::Refused { refusal: Refusal::NonceMismatch { expected } }) => Err(*expected),
+                    _ => Ok(()),
+                };
+                let _ = reply.send(res);
+            }
+            Command::SweepFees { amount, reply } => {
+                let res = match exec(&mut ex, &mut wal, EngineCommand::SweepFees { amount }, now).first() {
+                    Some(EngineEvent::FeesSwept { amount, balance }) => Ok((*amount, *balance)),
+                    _ => Err(ex.engine.treasury.balance),
+                };
+                let _ = reply.send(res);
+            }
+            Command::ResolveDeficit { trader, reply } => {
+                let res = match exec(&mut ex, &mut wal, EngineCommand::ResolveDeficit { trader }, now).first() {
+                    Some(EngineEvent::DeficitResolved { ids, .. }) => Ok(ids.clone()),
+                    Some(EngineEvent::Refused { refusal }) => Err(refusal.clone()),
+                    _ => Err(Refusal::NoOpenDeficit),
+                };
+                let _ = reply.send(res);
+            }
+            Command::PlaceAlgo(req, reply) => { let _ = reply.send(ex.algos.insert(req, now)); }
+            Command::AlgoNext(id, reply) => { let step = ex.algo_next(id, now); let _ = reply.send(step); }
+            Command::AlgoPlaced { id, qty, result, reply } => {
+                let trader = ex.algos.parents.get(&id).map(|p| p.trader.clone()).unwrap_or_default();
+                let (orphan, more) = ex.algos.child_placed(id, qty, result);
+                // parent was cancelled while this child was in flight
+                if let Some(child) = orphan { cancel(&mut ex, &mut wal, &trader, child, now); }
+                let _ = reply.send(more);
+            }
+            Command::CancelAlgo { trader, id, reply } => {
+                // resting children are pulled now; the driver sees the status on its next step
+                let out = ex.algos.cancel(&trader, id)
+                    .map(|children| children.into_iter().filter_map(|c| cancel(&mut ex, &mut wal, &trader, c, now)).collect());
+                let _ = reply.send(out);
+            }
+            Command::Tick(reply) => {
+                #[cfg(not(feature = "onchain"))]
+                exec(&mut ex, &mut wal, EngineCommand::Tick { txs: None }, now);
+                // in on-chain mode, only match when chain is active and call succeeds; otherwise keep orders queued
+                #[cfg(feature = "onchain")]
+                {
+                    // sweep and clear self-trades off the top, then settle one match at a time
+                    exec(&mut ex, &mut wal, EngineCommand::Tick { txs: Some(Vec::new()) }, now);
+                    while let Some(m) = ex.engine.peek_match() {
+                        if !chain.is_active() { break; }
+                        // an order that never made it on-chain cannot be settled there
+                        let (Some(buy_id), Some(sell_id)) = (m.buy_onchain_id, m.sell_onchain_id) else { break };
+                        match chain.match_orders(buy_id, sell_id, m.price, m.maker_side == engine::Side::Buy, ex.engine.match_fees(&m)).await {
+                            Ok(Some(tx)) => { exec(&mut ex, &mut wal, EngineCommand::Tick { txs: Some(vec![Some(tx)]) }, now); }
+                            // match failed: leave the book alone and retry next tick
+                            Ok(None) | Err(_) => break,
+                        }
+                    }
+                }
+                ex.end_tick();
+                let _ = reply.send(());
+            }
+            Command::Read(f) => f(&ex),
+        }
+        if mutates { ex.publish_book(); }
+    }
+}
+
+impl Sequencer {
+    async fn call<T>(&self, make: impl FnOnce(Reply<T>) -> Command) -> T {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(make(reply)).await.expect("sequencer stopped");
+        rx.await.expect("sequencer dropped reply")
+    }
+
+    /// The time commands are stamped with.
+    pub fn now(&self) -> u64 { self.clock.now() }
+
+    /// Run `f` against the current state and return its result.
+    pub async fn read<R: Send + 'static>(&self, f: impl FnOnce(&Exchange) -> R + Send + 'static) -> R {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Command::Read(Box::new(move |ex| { let _ = reply.send(f(ex)); }))).await.expect("sequencer stopped");
+        rx.await.expect("sequencer dropped reply")
+    }
+
+    pub async fn place_order(&self, req: PlaceOrderReq) -> Result<PlaceOrderResp, ApiError> { self.call(|r| Command::PlaceOrder(req, r)).await }
+    pub async fn cancel_order(&self, req: CancelOrderReq) -> Result<serde_json::Value, ApiError> { self.call(|r| Command::CancelOrder(req, r)).await }
+    pub async fn deposit(&self, trader: String, amount: i128, asset: Option<String>) -> Result<(), Refusal> { self.call(|reply| Command::Deposit { trader, amount, asset, reply }).await }
+    pub async fn withdraw(&self, trader: String, amount: i128, asset: Option<String>) -> Result<(), Refusal> { self.call(|reply| Command::Withdraw { trader, amount, asset, reply }).await }
+    pub async fn set_collateral_asset(&self, asset: String, config: CollateralAsset) -> Result<(), Refusal> { self.call(|reply| Command::SetCollateralAsset { asset, config, reply }).await }
+    pub async fn set_collateral_price(&self, asset: String, price: i128) -> Result<(), Refusal> { self.call(|reply| Command::SetCollateralPrice { asset, price, reply }).await }
+    pub async fn set_oracle(&self, price: i128) { self.call(|r| Command::SetOracle(price, r)).await }
+    pub async fn step_oracle(&self, delta: i128, min: i128, max: i128) -> i128 { self.call(|reply| Command::StepOracle { delta, min, max, reply }).await }
+    pub async fn set_fees(&self, schedule: FeeSchedule) -> Result<(), String> { self.call(|reply| Command::SetFees { schedule, reply }).await }
+    pub async fn set_fee_override(&self, trader: String, rates: Option<FeeRates>) -> Result<(), String> { self.call(|reply| Command::SetFeeOverride { trader, rates, reply }).await }
+    pub async fn set_risk_limits(&self, limits: RiskLimits) -> Result<(), Refusal> { self.call(|reply| Command::SetRiskLimits { limits, reply }).await }
+    pub async fn set_stp_default(&self, trader: String, mode: StpMode) { self.call(|reply| Command::SetStpDefault { trader, mode, reply }).await }
+    pub async fn create_sub_account(&self, master: String, sub: String) -> Result<(), Refusal> { self.call(|reply| Command::CreateSubAccount { master, sub, reply }).await }
+    pub async fn transfer(&self, from: String, to: String, amount: i128) -> Result<(), Refusal> { self.call(|reply| Command::Transfer { from, to, amount, reply }).await }
+    #[cfg(feature = "signing")]
+    pub async fn consume_nonce(&self, trader: String, nonce: u64) -> Result<(), u64> { self.call(|reply| Command::ConsumeNonce { trader, nonce, reply }).await }
+    pub async fn sweep_fees(&self, amount: Option<i128>) -> Result<(i128, i128), i128> { self.call(|reply| Command::SweepFees { amount, reply }).await }
+    pub async fn resolve_deficit(&self, trader: String) -> Result<Vec<u64>, Refusal> { self.call(|reply| Command::ResolveDeficit { trader, reply }).await }
+    pub async fn place_algo(&self, req: AlgoReq) -> u64 { self.call(|r| Command::PlaceAlgo(req, r)).await }
+    pub async fn algo_next(&self, id: u64) -> AlgoStep { self.call(|r| Command::AlgoNext(id, r)).await }
+    pub async fn algo_placed(&self, id: u64, qty: i128, result: Result<u64, String>) -> bool { self.call(|reply| Command::AlgoPlaced { id, qty, result, reply }).await }
+    pub async fn cancel_algo(&self, trader: String, id: u64) -> Option<Vec<u64>> { self.call(|reply| Command::CancelAlgo { trader, id, reply }).await }
+    pub async fn tick(&self) { self.call(Command::Tick).await }
+}
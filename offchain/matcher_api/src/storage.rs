@@ -0,0 +1,521 @@
+// Optional history store for analysts. A writer task follows the event bus,
+// gathers what changed (accounts and their collateral asset balances, orders,
+// fills, liquidations, deficits, ledger entries) and hands it to a `Storage`
+// backend in batches on a blocking thread, so the sequencer never waits on
+// the database; a resync copies the engine state and walks it off the
+// sequencer too. `SqliteStorage` (feature `sqlite`) is the real backend;
+// tests use `MemoryStorage`, which keeps the same records in maps. The WAL,
+// not this, is what restores state.
+#![cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+use engine::{Account, Deficit, EngineState, Fill, LedgerEntry, OrderRecord};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::events::{self, ExchangeEvent, Published};
+use crate::sequencer::Sequencer;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// Latest balances; upserted.
+    Account { trader: String, account: Account, ts: u64 },
+    /// Latest balance of a collateral asset other than the settlement currency; upserted.
+    AssetBalance { trader: String, asset: String, amount: i128, ts: u64 },
+    /// Latest lifecycle state; upserted.
+    Order(OrderRecord),
+    /// Appended once per (trade, order).
+    Fill { trader: String, fill: Fill },
+    Liquidation { trader: String, mark: i128, ts: u64 },
+    /// Appended once per (trader, seq).
+    Ledger(LedgerEntry),
+    /// Latest state, including resolution; upserted.
+    Deficit(Deficit),
+}
+
+pub trait Storage: Send + 'static {
+    /// Write a batch atomically.
+    fn write(&mut self, batch: &[Record]) -> anyhow::Result<()>;
+    /// Highest trade id with fills stored, to catch up after a restart or a lag.
+    fn last_trade_id(&self) -> anyhow::Result<u64>;
+    /// Highest ledger seq stored per trader, for the same reason.
+    fn ledger_seqs(&self) -> anyhow::Result<BTreeMap<String, u64>>;
+}
+
+pub struct StorageConfig {
+    pub flush_every: Duration,
+    pub max_batch: usize, // events; flush early once this many are pending
+}
+
+/// Accounts and orders touched since the last flush, plus records taken
+/// straight from events.
+#[derive(Default)]
+struct Pending {
+    traders: BTreeSet<String>,
+    orders: BTreeSet<u64>,
+    deficits: BTreeSet<u64>,
+    records: Vec<Record>,
+    events: usize,
+    // events were missed: rewrite every account, order and deficit and any trades and ledger entries not stored yet
+    resync: bool,
+}
+
+/// How far the store got; only read on resync.
+#[derive(Default)]
+struct Stored {
+    trade_id: u64,
+    ledger: BTreeMap<String, u64>,
+}
+
+impl Pending {
+    fn add(&mut self, ev: &ExchangeEvent) {
+        use ExchangeEvent::*;
+        match ev {
+            OrderAccepted { id, trader, .. } | OrderRejected { id, trader, .. } | OrderFilled { id, trader, .. } | OrderCancelled { id, trader, .. } => {
+                self.orders.insert(*id);
+                self.traders.insert(trader.clone());
+            }
+            Trade { trade, .. } => {
+                let mut traders = vec![&trade.buy_trader];
+                if trade.sell_trader != trade.buy_trader { traders.push(&trade.sell_trader); }
+                for trader in traders {
+                    self.records.extend(trade.fills_for(trader).into_iter().map(|fill| Record::Fill { trader: trader.clone(), fill }));
+                }
+            }
+            Liquidation { trader, mark, ts } => {
+                self.records.push(Record::Liquidation { trader: trader.clone(), mark: *mark, ts: *ts });
+                self.traders.insert(trader.clone());
+            }
+            Deficit { deficit } => {
+                self.deficits.insert(deficit.id);
+                self.traders.insert(deficit.trader.clone());
+            }
+            DeficitResolved { ids, .. } => self.deficits.extend(ids),
+            // a new sub-account has no ledger entries yet
+            SubAccountCreated { sub, .. } => { self.traders.insert(sub.clone()); }
+            Ledger { entry } => {
+                self.records.push(Record::Ledger(entry.clone()));
+                self.traders.insert(entry.trader.clone());
+            }
+            _ => return,
+        }
+        self.events += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.events == 0 && !self.resync
+    }
+
+    /// Build the batch from the engine's current state.
+    fn collect(self, engine: &EngineState, stored: Stored) -> Vec<Record> {
+        // the settlement balance and every other collateral asset the trader holds
+        let account = |trader: &String| -> Vec<Record> {
+            let Some(a) = engine.accounts.get(trader) else { return Vec::new() };
+            let ts = engine.now;
+            let assets = engine.asset_balances.get(trader).into_iter().flatten()
+                .map(|(asset, amount)| Record::AssetBalance { trader: trader.clone(), asset: asset.clone(), amount: *amount, ts });
+            std::iter::once(Record::Account { trader: trader.clone(), account: a.clone(), ts }).chain(assets).collect()
+        };
+        let mut out: Vec<Record> = if self.resync {
+            let mut all: Vec<Record> = engine.accounts.keys().flat_map(account).collect();
+            all.extend(engine.orders.values().cloned().map(Record::Order));
+            all.extend(engine.deficits.iter().cloned().map(Record::Deficit));
+            for t in engine.trades.iter().filter(|t| t.id > stored.trade_id) {
+                let mut traders = vec![&t.buy_trader];
+                if t.sell_trader != t.buy_trader { traders.push(&t.sell_trader); }
+                for trader in traders {
+                    all.extend(t.fills_for(trader).into_iter().map(|fill| Record::Fill { trader: trader.clone(), fill }));
+                }
+            }
+            for trader in engine.ledger.traders() {
+                let after = stored.ledger.get(trader).copied().unwrap_or(0);
+                all.extend(engine.ledger.page(trader, after, usize::MAX).iter().cloned().map(Record::Ledger));
+            }
+            all
+        } else {
+            let mut some: Vec<Record> = self.traders.iter().flat_map(account).collect();
+            some.extend(self.orders.iter().filter_map(|id| engine.orders.get(id)).cloned().map(Record::Order));
+            some.extend(engine.deficits.iter().filter(|d| self.deficits.contains(&d.id)).cloned().map(Record::Deficit));
+            some
+        };
+        out.extend(self.records);
+        out
+    }
+}
+
+/// Follow the bus and write batches until it closes. Starts with a resync so
+/// state recovered at startup is stored too.
+pub async fn run(seq: Sequencer, mut rx: broadcast::Receiver<Published>, mut store: Box<dyn Storage>, cfg: StorageConfig) {
+    let mut pending = Pending { resync: true, ..Default::default() };
+    let mut timer = tokio::time::interval(cfg.flush_every);
+    loop {
+        let closed = tokio::select! {
+            ev = events::next(&mut rx) => match ev {
+                Some(Ok(p)) => {
+                    pending.add(&p.event);
+                    if pending.events < cfg.max_batch { continue; }
+                    false
+                }
+                Some(Err(missed)) => {
+                    warn!(target="arbz", "storage writer skipped {} events, resyncing", missed);
+                    pending.resync = true;
+                    continue;
+                }
+                None => true,
+            },
+            _ = timer.tick() => false,
+        };
+        if !pending.is_empty() {
+            let (s, ok) = flush(&seq, store, std::mem::take(&mut pending)).await;
+            store = s;
+            pending.resync = !ok;
+        }
+        if closed { return; }
+    }
+}
+
+// Returns the store and whether the batch was written.
+async fn flush(seq: &Sequencer, store: Box<dyn Storage>, pending: Pending) -> (Box<dyn Storage>, bool) {
+    let write = |mut store: Box<dyn Storage>, batch: Vec<Record>| { let res = store.write(&batch); (store, res) };
+    let task = if pending.resync {
+        // a resync walks every account, order and ledger entry: copy the state
+        // (as a snapshot does) and do that here, not in the sequencer
+        let engine = seq.read(|ex| ex.engine.clone()).await;
+        tokio::task::spawn_blocking(move || {
+            let stored = Stored { trade_id: store.last_trade_id().unwrap_or(0), ledger: store.ledger_seqs().unwrap_or_default() };
+            let batch = pending.collect(&engine, stored);
+            write(store, batch)
+        })
+    } else {
+        let batch = seq.read(move |ex| pending.collect(&ex.engine, Stored::default())).await;
+        tokio::task::spawn_blocking(move || write(store, batch))
+    };
+    let (store, res) = task.await.expect("storage writer panicked");
+    // everything but the liquidation rows comes back on the next resync
+    if let Err(e) = &res { warn!(target="arbz", "storage write failed, batch dropped: {}", e); }
+    (store, res.is_ok())
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStorage;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use rusqlite::{params, Connection};
+    use serde::Serialize;
+
+    // Schema versions, applied in order; `PRAGMA user_version` is the number applied.
+    // Never edit one that has shipped, add a new one.
+    const MIGRATIONS: &[&str] = &[
+        // 1: initial schema
+        "CREATE TABLE accounts (
+            trader TEXT PRIMARY KEY,
+            collateral INTEGER NOT NULL,
+            locked_margin INTEGER NOT NULL,
+            updated_ts INTEGER NOT NULL
+        );
+        CREATE TABLE orders (
+            id INTEGER PRIMARY KEY,
+            trader TEXT NOT NULL,
+            client_order_id TEXT,
+            side TEXT NOT NULL,
+            price INTEGER NOT NULL,
+            qty INTEGER NOT NULL,
+            leverage INTEGER NOT NULL,
+            status TEXT NOT NULL,
+            filled_qty INTEGER NOT NULL,
+            avg_fill_price INTEGER,
+            fees_paid INTEGER NOT NULL,
+            reject_reason TEXT,
+            created_ts INTEGER NOT NULL,
+            closed_ts INTEGER
+        );
+        CREATE INDEX orders_trader ON orders (trader, id);
+        CREATE TABLE fills (
+            trade_id INTEGER NOT NULL,
+            order_id INTEGER NOT NULL,
+            trader TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            side TEXT NOT NULL,
+            price INTEGER NOT NULL,
+            qty INTEGER NOT NULL,
+            fee INTEGER NOT NULL,
+            liquidity TEXT NOT NULL,
+            PRIMARY KEY (trade_id, order_id)
+        );
+        CREATE INDEX fills_trader ON fills (trader, trade_id);
+        CREATE TABLE liquidations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            trader TEXT NOT NULL,
+            mark INTEGER NOT NULL,
+            ts INTEGER NOT NULL
+        );
+        -- ledger rows are the engine's journal, keyed by per-trader seq
+        CREATE TABLE ledger (
+            trader TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            ts INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            balance_after INTEGER NOT NULL,
+            reference TEXT,
+            PRIMARY KEY (trader, seq)
+        );",
+        // 2: the rate each fill was charged at
+        "ALTER TABLE fills ADD COLUMN fee_bps INTEGER NOT NULL DEFAULT 0;",
+        // 3: ledger entries in collateral assets other than the settlement currency
+        "ALTER TABLE ledger ADD COLUMN asset TEXT;",
+        // 4: collateral asset balances and account deficits
+        "CREATE TABLE asset_balances (
+            trader TEXT NOT NULL,
+            asset TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            updated_ts INTEGER NOT NULL,
+            PRIMARY KEY (trader, asset)
+        );
+        CREATE TABLE deficits (
+            id INTEGER PRIMARY KEY,
+            trader TEXT NOT NULL,
+            ts INTEGER NOT NULL,
+            mark INTEGER NOT NULL,
+            shortfall INTEGER NOT NULL,
+            absorbed INTEGER NOT NULL,
+            uncovered INTEGER NOT NULL,
+            resolved_ts INTEGER
+        );",
+    ];
+
+    pub struct SqliteStorage {
+        conn: Connection,
+    }
+
+    impl SqliteStorage {
+        /// Open (or create) the database and bring its schema up to date.
+        pub fn open(path: &str) -> anyhow::Result<Self> {
+            let mut conn = Connection::open(path)?;
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            migrate(&mut conn)?;
+            Ok(Self { conn })
+        }
+    }
+
+    fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+        let applied: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        for (i, sql) in MIGRATIONS.iter().enumerate().skip(applied) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(sql)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+
+    // amounts are i128 in memory; SQLite integers are 64-bit
+    fn int(v: i128) -> rusqlite::Result<i64> {
+        i64::try_from(v).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+    }
+
+    // wire name of a serde enum, e.g. "partially_filled"
+    fn name<T: Serialize>(v: &T) -> String {
+        serde_json::to_value(v).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default()
+    }
+
+    impl Storage for SqliteStorage {
+        fn write(&mut self, batch: &[Record]) -> anyhow::Result<()> {
+            let tx = self.conn.transaction()?;
+            for r in batch {
+                match r {
+                    Record::Account { trader, account, ts } => {
+                        tx.prepare_cached("INSERT INTO accounts (trader, collateral, locked_margin, updated_ts) VALUES (?1, ?2, ?3, ?4)
+                            ON CONFLICT (trader) DO UPDATE SET collateral = ?2, locked_margin = ?3, updated_ts = ?4")?
+                            .execute(params![trader, int(account.collateral)?, int(account.locked_margin)?, ts])?;
+                    }
+                    Record::AssetBalance { trader, asset, amount, ts } => {
+                        tx.prepare_cached("INSERT INTO asset_balances (trader, asset, amount, updated_ts) VALUES (?1, ?2, ?3, ?4)
+                            ON CONFLICT (trader, asset) DO UPDATE SET amount = ?3, updated_ts = ?4")?
+                            .execute(params![trader, asset, int(*amount)?, ts])?;
+                    }
+                    Record::Order(o) => {
+                        tx.prepare_cached("INSERT OR REPLACE INTO orders (id, trader, client_order_id, side, price, qty, leverage, status, filled_qty, avg_fill_price, fees_paid, reject_reason, created_ts, closed_ts)
+                            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)")?
+                            .execute(params![o.id, o.trader, o.client_order_id, name(&o.side), int(o.price)?, int(o.qty)?, o.leverage, name(&o.status),
+                                int(o.filled_qty)?, o.avg_fill_price.map(int).transpose()?, int(o.fees_paid)?, o.reject_reason, o.created_ts, o.closed_ts])?;
+                    }
+                    Record::Fill { trader, fill: f } => {
+                        tx.prepare_cached("INSERT OR IGNORE INTO fills (trade_id, order_id, trader, ts, side, price, qty, fee, fee_bps, liquidity) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)")?
+                            .execute(params![f.trade_id, f.order_id, trader, f.ts, name(&f.side), int(f.price)?, int(f.qty)?, int(f.fee)?, f.fee_bps, name(&f.liquidity)])?;
+                    }
+                    Record::Liquidation { trader, mark, ts } => {
+                        tx.prepare_cached("INSERT INTO liquidations (trader, mark, ts) VALUES (?1, ?2, ?3)")?
+                            .execute(params![trader, int(*mark)?, ts])?;
+                    }
+                    Record::Ledger(e) => {
+                        tx.prepare_cached("INSERT OR IGNORE INTO ledger (trader, seq, ts, kind, asset, amount, balance_after, reference) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
+                            .execute(params![e.trader, e.seq, e.ts, name(&e.kind), e.asset, int(e.amount)?, int(e.balance_after)?, e.reference])?;
+                    }
+                    Record::Deficit(d) => {
+                        tx.prepare_cached("INSERT OR REPLACE INTO deficits (id, trader, ts, mark, shortfall, absorbed, uncovered, resolved_ts) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
+                            .execute(params![d.id, d.trader, d.ts, int(d.mark)?, int(d.shortfall)?, int(d.absorbed)?, int(d.uncovered)?, d.resolved_ts])?;
+                    }
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        fn last_trade_id(&self) -> anyhow::Result<u64> {
+            Ok(self.conn.query_row("SELECT COALESCE(MAX(trade_id), 0) FROM fills", [], |r| r.get(0))?)
+        }
+
+        fn ledger_seqs(&self) -> anyhow::Result<BTreeMap<String, u64>> {
+            let mut stmt = self.conn.prepare("SELECT trader, MAX(seq) FROM ledger GROUP BY trader")?;
+            let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+            Ok(rows.collect::<rusqlite::Result<_>>()?)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_migrate_and_rewrite() {
+            let mut db = SqliteStorage { conn: Connection::open_in_memory().unwrap() };
+            migrate(&mut db.conn).unwrap();
+            migrate(&mut db.conn).unwrap(); // already current
+            let version: usize = db.conn.pragma_query_value(None, "user_version", |r| r.get(0)).unwrap();
+            assert_eq!(version, MIGRATIONS.len());
+            let batch = super::super::tests::sample_batch();
+            db.write(&batch).unwrap();
+            db.write(&batch).unwrap();
+            let count = |table: &str| db.conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |r| r.get::<_, i64>(0)).unwrap();
+            assert_eq!((count("accounts"), count("orders"), count("fills"), count("ledger")), (2, 2, 2, 4));
+            assert_eq!(db.last_trade_id().unwrap(), 1);
+            assert_eq!(db.ledger_seqs().unwrap()["alice"], 2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::{Exchange, ExchangeConfig};
+    use crate::PlaceOrderReq;
+    use engine::{EngineCommand, EngineConfig, LedgerKind};
+    use std::collections::HashMap;
+
+    /// The records kept in maps, same semantics as the database.
+    #[derive(Default)]
+    pub struct MemoryStorage {
+        pub accounts: HashMap<String, (Account, u64)>,
+        pub orders: BTreeMap<u64, OrderRecord>,
+        pub fills: BTreeMap<(u64, u64), (String, Fill)>, // (trade id, order id)
+        pub liquidations: Vec<(String, i128, u64)>,
+        pub ledger: BTreeMap<(String, u64), LedgerEntry>, // (trader, seq)
+        pub assets: BTreeMap<(String, String), (i128, u64)>, // (trader, asset)
+        pub deficits: BTreeMap<u64, Deficit>,
+    }
+
+    impl Storage for MemoryStorage {
+        fn write(&mut self, batch: &[Record]) -> anyhow::Result<()> {
+            for r in batch.iter().cloned() {
+                match r {
+                    Record::Account { trader, account, ts } => { self.accounts.insert(trader, (account, ts)); }
+                    Record::Order(o) => { self.orders.insert(o.id, o); }
+                    Record::Fill { trader, fill } => { self.fills.entry((fill.trade_id, fill.order_id)).or_insert((trader, fill)); }
+                    Record::Liquidation { trader, mark, ts } => self.liquidations.push((trader, mark, ts)),
+                    Record::Ledger(e) => { self.ledger.entry((e.trader.clone(), e.seq)).or_insert(e); }
+                    Record::AssetBalance { trader, asset, amount, ts } => { self.assets.insert((trader, asset), (amount, ts)); }
+                    Record::Deficit(d) => { self.deficits.insert(d.id, d); }
+                }
+            }
+            Ok(())
+        }
+
+        fn last_trade_id(&self) -> anyhow::Result<u64> {
+            Ok(self.fills.keys().next_back().map(|(t, _)| *t).unwrap_or(0))
+        }
+
+        fn ledger_seqs(&self) -> anyhow::Result<BTreeMap<String, u64>> {
+            Ok(self.ledger.keys().map(|(t, seq)| (t.clone(), *seq)).collect())
+        }
+    }
+
+    fn exchange() -> (Exchange, broadcast::Receiver<Published>) {
+        let bus = events::bus(1024);
+        let rx = bus.subscribe();
+        let cfg = ExchangeConfig { engine: EngineConfig { client_id_window_secs: 60, order_retention_secs: 3_600, trade_retention: 10 }, candle_retention: 10 };
+        let mut ex = Exchange::new(cfg, bus);
+        ex.apply(EngineCommand::SetFees { maker_bps: 20, taker_bps: 50 }, 1_000);
+        ex.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 100_000, asset: None }, 1_000);
+        ex.apply(EngineCommand::Deposit { trader: "bob".into(), amount: 100_000, asset: None }, 1_000);
+        for (trader, side) in [("alice", "buy"), ("bob", "sell")] {
+            let req: PlaceOrderReq = serde_json::from_value(serde_json::json!({"trader":trader,"side":side,"price":100,"qty":5,"leverage":2,"ttl_secs":600,"is_limit":true})).unwrap();
+            ex.apply(EngineCommand::PlaceOrder(req.to_engine(None)), 1_000);
+        }
+        ex.apply(EngineCommand::Tick { txs: None }, 1_000);
+        ex.end_tick();
+        (ex, rx)
+    }
+
+    pub(super) fn sample_batch() -> Vec<Record> {
+        let (ex, mut rx) = exchange();
+        let mut pending = Pending::default();
+        while let Ok(p) = rx.try_recv() { pending.add(&p.event); }
+        pending.collect(&ex.engine, Stored::default())
+    }
+
+    #[test]
+    fn test_batch_from_events() {
+        let mut store = MemoryStorage::default();
+        store.write(&sample_batch()).unwrap();
+        assert_eq!(store.accounts["alice"].0, Account { collateral: 100_000 - 1, locked_margin: 250 });
+        assert!(store.orders.values().all(|o| o.status == engine::OrderStatus::Filled));
+        assert_eq!(store.fills.len(), 2);
+        // alice's bid was booked first, so she is the maker
+        assert!(store.fills.values().all(|(t, f)| f.fee_bps == if t == "alice" { 20 } else { 50 }));
+        let kinds: Vec<(LedgerKind, i128, i128)> = store.ledger.values().filter(|e| e.trader == "alice").map(|e| (e.kind, e.amount, e.balance_after)).collect();
+        assert_eq!(kinds, vec![(LedgerKind::Deposit, 100_000, 100_000), (LedgerKind::Fee, -1, 100_000 - 1)]);
+    }
+
+    #[test]
+    fn test_asset_balances_and_deficits() {
+        let (mut ex, mut rx) = exchange();
+        let eth = engine::CollateralAsset { haircut_bps: 0, source: engine::PriceSource::Feed, price: 0, price_ts: 0 };
+        ex.apply(EngineCommand::SetCollateralAsset { asset: "ETH".into(), config: eth }, 1_001);
+        ex.apply(EngineCommand::Deposit { trader: "alice".into(), amount: 2, asset: Some("ETH".into()) }, 1_001);
+        // carol goes long with 30 and is wiped out below her entry
+        ex.apply(EngineCommand::Deposit { trader: "carol".into(), amount: 30, asset: None }, 1_001);
+        for (trader, side) in [("carol", "buy"), ("bob", "sell")] {
+            let req: PlaceOrderReq = serde_json::from_value(serde_json::json!({"trader":trader,"side":side,"price":100,"qty":1,"leverage":5,"ttl_secs":600,"is_limit":true})).unwrap();
+            ex.apply(EngineCommand::PlaceOrder(req.to_engine(None)), 1_001);
+        }
+        ex.apply(EngineCommand::Tick { txs: None }, 1_001);
+        ex.apply(EngineCommand::SetOracle { price: 50, source: engine::OracleSource::Admin }, 1_002);
+        ex.apply(EngineCommand::Tick { txs: None }, 1_002);
+        ex.apply(EngineCommand::ResolveDeficit { trader: "carol".into() }, 1_003);
+        let mut pending = Pending::default();
+        while let Ok(p) = rx.try_recv() { pending.add(&p.event); }
+        let mut store = MemoryStorage::default();
+        store.write(&pending.collect(&ex.engine, Stored::default())).unwrap();
+        assert_eq!(store.assets[&("alice".to_string(), "ETH".to_string())], (2, 1_003));
+        assert_eq!(store.deficits.values().map(|d| (d.trader.as_str(), d.resolved_ts)).collect::<Vec<_>>(), vec![("carol", Some(1_003))]);
+        // and a resync writes the same
+        let mut again = MemoryStorage::default();
+        again.write(&Pending { resync: true, ..Default::default() }.collect(&ex.engine, Stored::default())).unwrap();
+        assert_eq!((again.assets, again.deficits), (store.assets, store.deficits));
+    }
+
+    #[test]
+    fn test_resync_is_idempotent() {
+        let mut store = MemoryStorage::default();
+        store.write(&sample_batch()).unwrap();
+        let (ex, _rx) = exchange();
+        let stored = Stored { trade_id: store.last_trade_id().unwrap(), ledger: store.ledger_seqs().unwrap() };
+        let resync = Pending { resync: true, ..Default::default() }.collect(&ex.engine, stored);
+        assert!(!resync.iter().any(|r| matches!(r, Record::Fill { .. } | Record::Ledger(_))));
+        store.write(&Pending { resync: true, ..Default::default() }.collect(&ex.engine, Stored::default())).unwrap();
+        assert_eq!((store.accounts.len(), store.orders.len(), store.fills.len(), store.ledger.len()), (2, 2, 2, 4));
+    }
+}
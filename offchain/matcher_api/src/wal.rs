@@ -0,0 +1,127 @@
+// Write-ahead command log and snapshots. The sequencer appends every engine
+// command that changed state to `wal.log` (one `LogEntry` JSON line, fsync'd)
+// before it replies, and every `snapshot_every` commands writes the engine
+// state to `snapshot.json` and empties the log. On startup the snapshot is
+// loaded and the log tail re-applied with the logged timestamps, so the state
+// comes back exactly. The engine's `replay` binary reads the same files.
+use engine::{EngineCommand, EngineState, LogEntry, Snapshot};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use tracing::warn;
+
+pub struct Wal {
+    dir: PathBuf,
+    log: File,
+    next_seq: u64,
+    since_snapshot: u64,
+    snapshot_every: u64,
+}
+
+/// What `Wal::open` found on disk.
+pub struct Recovered {
+    pub snapshot: Option<EngineState>,
+    pub tail: Vec<LogEntry>,
+}
+
+impl Wal {
+    pub fn open(dir: impl Into<PathBuf>, snapshot_every: u64) -> io::Result<(Self, Recovered)> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let (last_seq, snapshot) = match fs::read(dir.join("snapshot.json")) {
+            Ok(bytes) => {
+                let s: Snapshot = serde_json::from_slice(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                (s.last_seq, Some(s.state))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, None),
+            Err(e) => return Err(e),
+        };
+        let path = dir.join("wal.log");
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // every acknowledged entry ends in a newline; anything after the last one is a torn write
+        let good = bytes.iter().rposition(|b| *b == b'\n').map(|i| i + 1).unwrap_or(0);
+        let mut tail = Vec::new();
+        let mut next_seq = last_seq + 1;
+        for (i, line) in bytes[..good].lines().enumerate() {
+            match serde_json::from_str::<LogEntry>(&line?) {
+                // already covered by the snapshot (crash between snapshot and truncate)
+                Ok(e) if e.seq <= last_seq => {}
+                Ok(e) => { next_seq = e.seq + 1; tail.push(e); }
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("wal.log line {}: {}", i + 1, e))),
+            }
+        }
+        let since_snapshot = tail.len() as u64;
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        // cut the torn entry off so the next append starts on a fresh line
+        if good < bytes.len() {
+            warn!(target="arbz", "dropping incomplete last log entry");
+            log.set_len(good as u64)?;
+            log.sync_all()?;
+        }
+        let wal = Wal { dir, log, next_seq, since_snapshot, snapshot_every: snapshot_every.max(1) };
+        Ok((wal, Recovered { snapshot, tail }))
+    }
+
+    /// Append and fsync one entry.
+    pub fn append(&mut self, ts: u64, cmd: EngineCommand) -> io::Result<()> {
+        let entry = LogEntry { seq: self.next_seq, ts, cmd };
+        let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        self.log.write_all(&line)?;
+        self.log.sync_data()?;
+        self.next_seq += 1;
+        self.since_snapshot += 1;
+        Ok(())
+    }
+
+    pub fn snapshot_due(&self) -> bool {
+        self.since_snapshot >= self.snapshot_every
+    }
+
+    /// Write `state` as of the last appended entry, then empty the log.
+    pub fn snapshot(&mut self, state: EngineState) -> io::Result<()> {
+        let snap = Snapshot { last_seq: self.next_seq - 1, state };
+        let tmp = self.dir.join("snapshot.json.tmp");
+        let mut f = File::create(&tmp)?;
+        serde_json::to_writer(&mut f, &snap).map_err(io::Error::other)?;
+        f.sync_all()?;
+        fs::rename(&tmp, self.dir.join("snapshot.json"))?;
+        // entries at or below last_seq are skipped on load, so a crash before this truncate is harmless
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(amount: i128) -> EngineCommand {
+        EngineCommand::Deposit { trader: "alice".into(), amount, asset: None }
+    }
+
+    #[test]
+    fn test_torn_entry_is_cut_before_appending() {
+        let dir = std::env::temp_dir().join(format!("wal-torn-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (mut wal, _) = Wal::open(&dir, 100).unwrap();
+        wal.append(1, deposit(10)).unwrap();
+        drop(wal);
+        // a crash mid-write leaves half a line behind
+        OpenOptions::new().append(true).open(dir.join("wal.log")).unwrap().write_all(b"{\"seq\":2,\"ts\":2,").unwrap();
+        let (mut wal, recovered) = Wal::open(&dir, 100).unwrap();
+        assert_eq!(recovered.tail.len(), 1);
+        wal.append(3, deposit(20)).unwrap();
+        drop(wal);
+        let (_, recovered) = Wal::open(&dir, 100).unwrap();
+        let seqs: Vec<(u64, u64)> = recovered.tail.iter().map(|e| (e.seq, e.ts)).collect();
+        assert_eq!(seqs, vec![(1, 1), (2, 3)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+// `/ws` subscription protocol. A connection starts with no subscriptions;
+// the client sends `subscribe`/`unsubscribe` requests naming channels and
+// gets an ack (with the current sequence number of each stream) or an error
+// back. Events are then delivered as `{"type":"event","channel",...,"seq","data"}`.
+// Schemas for every message live in `schemas/`.
+//
+// The private `account` channel needs a `token`: either ADMIN_TOKEN, or the
+// trader's own token from `GET /admin/account-token/:trader`, which is an
+// HMAC of the trader name under ADMIN_TOKEN and only opens that trader's stream.
+use axum::extract::ws::{Message, WebSocket};
+use axum::extract::{State, WebSocketUpgrade};
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use tracing::warn;
+
+use crate::events::{self, Channel, ExchangeEvent, Stream};
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Subscribe(SubscribeReq),
+    Unsubscribe(SubscribeReq),
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscribeReq {
+    #[serde(default)]
+    req_id: Option<u64>,
+    channels: Vec<String>,
+    // required for the private `account` channel
+    #[serde(default)]
+    trader: Option<String>,
+    // ADMIN_TOKEN or the trader's account token, to subscribe to `account`
+    #[serde(default)]
+    token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamAck {
+    channel: Channel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trader: Option<String>,
+    seq: u64, // last sequence number already published; the next event on the stream has seq + 1
+}
+
+// replies are serialized as soon as they are built, so the size spread does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply<'a> {
+    Subscribed { req_id: Option<u64>, streams: Vec<StreamAck> },
+    Unsubscribed { req_id: Option<u64>, streams: Vec<StreamAck> },
+    Error { req_id: Option<u64>, code: &'static str, message: String },
+    Event {
+        channel: Channel,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        trader: Option<&'a str>,
+        seq: u64,
+        data: Cow<'a, ExchangeEvent>,
+    },
+    /// The connection fell behind the event bus and `missed` events were dropped;
+    /// sequence gaps tell the client which streams to resubscribe.
+    Lagged { missed: u64 },
+}
+
+pub async fn ws(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(|socket| async move { handle(state, socket).await })
+}
+
+async fn handle(state: AppState, mut socket: WebSocket) {
+    // WS clients only listen; matching runs in matcher::run whether or not anyone is connected
+    let mut rx = state.events.subscribe();
+    // subscribed stream -> last seq delivered (or already covered by the ack)
+    let mut subs: HashMap<Stream, u64> = HashMap::new();
+    loop {
+        tokio::select! {
+            ev = events::next(&mut rx) => match ev {
+                Some(Ok(p)) => {
+                    for ((channel, trader), seq) in &p.seqs {
+                        let Some(last) = subs.get_mut(&(*channel, trader.clone())) else { continue };
+                        // published before the subscription was acked
+                        if *seq <= *last { continue; }
+                        *last = *seq;
+                        let out = Reply::Event { channel: *channel, trader: trader.as_deref(), seq: *seq, data: Cow::Borrowed(&p.event) };
+                        if !send(&mut socket, &out).await { return; }
+                    }
+                }
+                Some(Err(missed)) => {
+                    warn!(target="arbz", "ws subscriber skipped {} events", missed);
+                    if !send(&mut socket, &Reply::Lagged { missed }).await { return; }
+                }
+                None => return,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    for reply in request(&state, &mut subs, &text).await {
+                        if !send(&mut socket, &reply).await { return; }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, reply: &Reply<'_>) -> bool {
+    let text = serde_json::to_string(reply).unwrap_or_default();
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+/// Token that opens `trader`'s account stream; hex HMAC-SHA256 of the name under `admin_token`.
+pub fn account_token(admin_token: &str, trader: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(admin_token.as_bytes()).expect("hmac takes any key length");
+    mac.update(trader.as_bytes());
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// without ADMIN_TOKEN there is no token to check against, so nobody may read account streams
+fn may_read_account(state: &AppState, trader: &str, token: Option<&str>) -> bool {
+    let (Some(admin), Some(token)) = (state.admin_token.as_deref(), token) else { return false };
+    same(token, admin) || same(token, &account_token(admin, trader))
+}
+
+// compare without stopping at the first difference
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn error(req_id: Option<u64>, code: &'static str, message: impl Into<String>) -> Vec<Reply<'static>> {
+    vec![Reply::Error { req_id, code, message: message.into() }]
+}
+
+// Apply one client request; returns the ack or error, followed by snapshot events if any.
+async fn request(state: &AppState, subs: &mut HashMap<Stream, u64>, text: &str) -> Vec<Reply<'static>> {
+    let req: Request = match serde_json::from_str(text) {
+        Ok(r) => r,
+        Err(e) => return error(None, "bad_request", e.to_string()),
+    };
+    let (subscribe, req) = match req { Request::Subscribe(r) => (true, r), Request::Unsubscribe(r) => (false, r) };
+    let mut streams: Vec<Stream> = Vec::new();
+    for name in &req.channels {
+        let Ok(channel) = serde_json::from_value::<Channel>(serde_json::Value::String(name.clone())) else {
+            return error(req.req_id, "unknown_channel", format!("unknown channel {:?}", name));
+        };
+        let trader = match (channel, &req.trader) {
+            (Channel::Account, Some(t)) => Some(t.clone()),
+            (Channel::Account, None) => return error(req.req_id, "trader_required", "the account channel needs a trader"),
+            _ => None,
+        };
+        // account streams carry balances and order ids
+        if let (true, Some(t)) = (subscribe, &trader) {
+            if !may_read_account(state, t, req.token.as_deref()) {
+                return error(req.req_id, "unauthorized", "the account channel needs the trader's account token or ADMIN_TOKEN");
+            }
+        }
+        streams.push((channel, trader));
+    }
+    if streams.is_empty() { return error(req.req_id, "bad_request", "channels must not be empty"); }
+
+    let lookup = streams.clone();
+    let (current, oracle, ticker) = state.seq.read(move |ex| {
+        let current: Vec<u64> = lookup.iter().map(|s| ex.seqs.current(s)).collect();
+        (current, (ex.engine.oracle.price, ex.engine.oracle_source), ex.last_ticker.clone())
+    }).await;
+    let acks: Vec<StreamAck> = streams.iter().zip(&current).map(|((channel, trader), seq)| StreamAck { channel: *channel, trader: trader.clone(), seq: *seq }).collect();
+    if !subscribe {
+        for s in &streams { subs.remove(s); }
+        return vec![Reply::Unsubscribed { req_id: req.req_id, streams: acks }];
+    }
+    for (s, seq) in streams.iter().zip(&current) { subs.insert(s.clone(), *seq); }
+    let mut out = vec![Reply::Subscribed { req_id: req.req_id, streams: acks }];
+    // latest value for channels that carry a level rather than a log, tagged with the acked seq
+    for ((channel, _), seq) in streams.iter().zip(&current) {
+        let data = match channel {
+            Channel::Oracle => match oracle { (price, Some(source)) => ExchangeEvent::oracle(price, source), (_, None) => continue },
+            Channel::Ticker => match &ticker { Some(t) => ExchangeEvent::Ticker(t.clone()), None => continue },
+            _ => continue,
+        };
+        out.push(Reply::Event { channel: *channel, trader: None, seq: *seq, data: Cow::Owned(data) });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_tokens_are_per_trader() {
+        let alice = account_token("s", "alice");
+        assert_eq!(alice.len(), 64);
+        assert_eq!(alice, account_token("s", "alice"));
+        assert_ne!(alice, account_token("s", "bob"));
+        assert_ne!(alice, account_token("t", "alice"));
+        assert!(same(&alice, &account_token("s", "alice")) && !same(&alice, "s"));
+    }
+}